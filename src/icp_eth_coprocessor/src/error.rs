@@ -0,0 +1,120 @@
+use candid::{CandidType, Deserialize};
+
+use ic_cdk::api::call::RejectionCode;
+
+use std::fmt;
+
+use crate::evm_rpc::{HttpOutcallError, JsonRpcError, ProviderError, RpcError, ValidationError};
+
+/// Errors of the calls to the EVM RPC canister and the threshold ECDSA API.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum CoprocessorError {
+    /// The JSON-RPC provider returned an error, e.g. because a transaction would revert.
+    JsonRpcError(JsonRpcError),
+    ProviderError(ProviderError),
+    HttpOutcallError(HttpOutcallError),
+    ValidationError(ValidationError),
+    /// The providers of a replicated call returned different results.
+    InconsistentResponses {
+        method: String,
+    },
+    /// The request could not be encoded.
+    InvalidRequest(String),
+    /// The response could not be interpreted.
+    InvalidResponse(String),
+    /// An inter-canister call, e.g. to the EVM RPC canister, was rejected.
+    CallRejected {
+        code: RejectionCode,
+        message: String,
+    },
+    SigningError(String),
+    /// The required fee exceeds the cap configured for the chain.
+    FeeCapExceeded {
+        required: u128,
+        cap: u128,
+    },
+    /// The callback transaction cannot be built or would not succeed.
+    InvalidTransaction(String),
+    /// An argument of a call to the canister is invalid, e.g. an unknown chain or subscription.
+    InvalidArgument(String),
+    /// The handler of a job failed to compute its result.
+    HandlerError(String),
+    /// The transaction calling the callback has been included in a block, but reverted.
+    CallbackReverted,
+    /// The nonce of the callback is ahead of the network, as a transaction with a lower nonce
+    /// is missing.
+    NonceTooHigh {
+        nonce: u128,
+    },
+}
+
+impl CoprocessorError {
+    /// Returns true if the failed operation may succeed when it is tried again later.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            CoprocessorError::ProviderError(ProviderError::TooFewCycles { .. })
+            | CoprocessorError::HttpOutcallError(_)
+            | CoprocessorError::InconsistentResponses { .. }
+            | CoprocessorError::InvalidResponse(_)
+            | CoprocessorError::FeeCapExceeded { .. }
+            | CoprocessorError::NonceTooHigh { .. } => true,
+            CoprocessorError::CallRejected { code, .. } => *code == RejectionCode::SysTransient,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for CoprocessorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoprocessorError::JsonRpcError(JsonRpcError { code, message }) => {
+                write!(f, "JSON-RPC error {}: {}", code, message)
+            }
+            CoprocessorError::InconsistentResponses { method } => {
+                write!(f, "Providers returned inconsistent responses to {}", method)
+            }
+            CoprocessorError::InvalidRequest(message)
+            | CoprocessorError::InvalidResponse(message)
+            | CoprocessorError::SigningError(message)
+            | CoprocessorError::InvalidTransaction(message)
+            | CoprocessorError::InvalidArgument(message)
+            | CoprocessorError::HandlerError(message) => write!(f, "{}", message),
+            CoprocessorError::CallbackReverted => write!(f, "The callback reverted"),
+            CoprocessorError::NonceTooHigh { nonce } => {
+                write!(f, "Nonce {} is ahead of the network", nonce)
+            }
+            CoprocessorError::CallRejected { code, message } => {
+                write!(f, "Call rejected ({:?}): {}", code, message)
+            }
+            CoprocessorError::FeeCapExceeded { required, cap } => write!(
+                f,
+                "Fee of {} wei per gas exceeds the cap of {} wei",
+                required, cap
+            ),
+            other => write!(f, "{:?}", other),
+        }
+    }
+}
+
+impl From<RpcError> for CoprocessorError {
+    fn from(error: RpcError) -> Self {
+        match error {
+            RpcError::JsonRpcError(e) => CoprocessorError::JsonRpcError(e),
+            RpcError::ProviderError(e) => CoprocessorError::ProviderError(e),
+            RpcError::HttpOutcallError(e) => CoprocessorError::HttpOutcallError(e),
+            RpcError::ValidationError(e) => CoprocessorError::ValidationError(e),
+        }
+    }
+}
+
+impl From<(RejectionCode, String)> for CoprocessorError {
+    fn from((code, message): (RejectionCode, String)) -> Self {
+        CoprocessorError::CallRejected { code, message }
+    }
+}
+
+impl From<CoprocessorError> for String {
+    fn from(error: CoprocessorError) -> Self {
+        error.to_string()
+    }
+}
@@ -0,0 +1,222 @@
+use candid::{CandidType, Deserialize, Int, Nat};
+
+use ethers_core::abi::{Event, EventExt, Function, HumanReadableParser, RawLog, Token};
+use ethers_core::types::{Address, H256, I256, U256};
+
+use std::str::FromStr;
+
+use crate::evm_rpc::LogEntry;
+
+/// A typed ABI value, used for decoded event parameters and the arguments of callbacks.
+/// Dynamic types (`string`, `bytes`, arrays) that are indexed event parameters are only
+/// available as the Keccak-256 hash of their value and decode to `FixedBytes`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum AbiValue {
+    Address(String),
+    Uint(Nat),
+    Int(Int),
+    Bool(bool),
+    String(String),
+    Bytes(Vec<u8>),
+    FixedBytes(Vec<u8>),
+    Array(Vec<AbiValue>),
+    FixedArray(Vec<AbiValue>),
+    Tuple(Vec<AbiValue>),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EventParam {
+    pub name: String,
+    pub indexed: bool,
+    pub value: AbiValue,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DecodedEvent {
+    pub name: String,
+    /// The canonical signature of the event, e.g. `NewJob(uint256)`.
+    pub signature: String,
+    pub params: Vec<EventParam>,
+}
+
+impl DecodedEvent {
+    pub fn param(&self, name: &str) -> Option<&AbiValue> {
+        self.params
+            .iter()
+            .find(|param| param.name == name)
+            .map(|param| &param.value)
+    }
+
+    /// Returns the value of an unsigned integer parameter.
+    pub fn uint(&self, name: &str) -> Result<U256, String> {
+        match self.param(name) {
+            Some(AbiValue::Uint(value)) => Ok(U256::from_big_endian(&value.0.to_bytes_be())),
+            Some(other) => Err(format!("Parameter {} is not a uint: {:?}", name, other)),
+            None => Err(format!("Event {} has no parameter {}", self.name, name)),
+        }
+    }
+}
+
+/// Parses an event from its human readable ABI, e.g. `event NewJob(uint256 job_id)`.
+pub fn parse_event(abi: &str) -> Result<Event, String> {
+    HumanReadableParser::parse_event(abi).map_err(|e| format!("Invalid event ABI {}: {}", abi, e))
+}
+
+/// Computes topic 0 of an event, i.e. the Keccak-256 hash of its signature.
+pub fn topic0(event: &Event) -> String {
+    format!("{:#x}", event.signature())
+}
+
+/// Decodes the indexed (topics) and non-indexed (data) parameters of a log against the ABI
+/// of the given event.
+pub fn decode_log(event: &Event, log: &LogEntry) -> Result<DecodedEvent, String> {
+    let topics = log
+        .topics
+        .iter()
+        .map(|topic| H256::from_str(topic).map_err(|e| format!("Invalid topic {}: {}", topic, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let data = hex::decode(log.data.trim_start_matches("0x"))
+        .map_err(|e| format!("Invalid log data: {}", e))?;
+
+    let parsed = event
+        .parse_log(RawLog { topics, data })
+        .map_err(|e| format!("Failed to decode {} log: {}", event.name, e))?;
+
+    let params = parsed
+        .params
+        .into_iter()
+        .map(|param| {
+            let indexed = event
+                .inputs
+                .iter()
+                .any(|input| input.name == param.name && input.indexed);
+            EventParam {
+                name: param.name,
+                indexed,
+                value: token_to_value(param.value),
+            }
+        })
+        .collect();
+
+    Ok(DecodedEvent {
+        name: event.name.clone(),
+        signature: event.abi_signature(),
+        params,
+    })
+}
+
+/// Parses a function from its human readable ABI, e.g.
+/// `function balanceOf(address owner) view returns (uint256)`.
+pub fn parse_function(abi: &str) -> Result<Function, String> {
+    HumanReadableParser::parse_function(abi)
+        .map_err(|e| format!("Invalid function ABI {}: {}", abi, e))
+}
+
+/// Encodes a call of a function with the given arguments.
+pub fn encode_call(function: &Function, args: &[AbiValue]) -> Result<Vec<u8>, String> {
+    let tokens = args
+        .iter()
+        .map(value_to_token)
+        .collect::<Result<Vec<_>, _>>()?;
+    function
+        .encode_input(&tokens)
+        .map_err(|e| format!("Arguments do not match {}: {}", function.signature(), e))
+}
+
+/// Decodes the return data of a function call.
+pub fn decode_output(function: &Function, data: &[u8]) -> Result<Vec<AbiValue>, String> {
+    let tokens = function.decode_output(data).map_err(|e| {
+        format!(
+            "Failed to decode the output of {}: {}",
+            function.signature(),
+            e
+        )
+    })?;
+    Ok(tokens.into_iter().map(token_to_value).collect())
+}
+
+fn token_to_value(token: Token) -> AbiValue {
+    match token {
+        Token::Address(address) => {
+            AbiValue::Address(ethers_core::utils::to_checksum(&address, None))
+        }
+        Token::Uint(value) => {
+            AbiValue::Uint(Nat::from_str(&value.to_string()).expect("U256 is a valid nat"))
+        }
+        Token::Int(value) => AbiValue::Int(
+            Int::from_str(&I256::from_raw(value).to_string()).expect("I256 is a valid int"),
+        ),
+        Token::Bool(value) => AbiValue::Bool(value),
+        Token::String(value) => AbiValue::String(value),
+        Token::Bytes(value) => AbiValue::Bytes(value),
+        Token::FixedBytes(value) => AbiValue::FixedBytes(value),
+        Token::Array(values) => AbiValue::Array(values.into_iter().map(token_to_value).collect()),
+        Token::FixedArray(values) => {
+            AbiValue::FixedArray(values.into_iter().map(token_to_value).collect())
+        }
+        Token::Tuple(values) => AbiValue::Tuple(values.into_iter().map(token_to_value).collect()),
+    }
+}
+
+/// Converts a value into an ABI token, e.g. to encode it as an argument of a function call.
+pub fn value_to_token(value: &AbiValue) -> Result<Token, String> {
+    let tokens = |values: &[AbiValue]| -> Result<Vec<Token>, String> {
+        values.iter().map(value_to_token).collect()
+    };
+    Ok(match value {
+        AbiValue::Address(address) => Token::Address(
+            Address::from_str(address)
+                .map_err(|e| format!("Invalid address {}: {}", address, e))?,
+        ),
+        AbiValue::Uint(value) => {
+            let bytes = value.0.to_bytes_be();
+            if bytes.len() > 32 {
+                return Err(format!("{} does not fit into a uint256", value));
+            }
+            Token::Uint(U256::from_big_endian(&bytes))
+        }
+        AbiValue::Int(value) => Token::Int(
+            I256::from_dec_str(&value.to_string())
+                .map_err(|e| format!("{} does not fit into an int256: {}", value, e))?
+                .into_raw(),
+        ),
+        AbiValue::Bool(value) => Token::Bool(*value),
+        AbiValue::String(value) => Token::String(value.clone()),
+        AbiValue::Bytes(value) => Token::Bytes(value.clone()),
+        AbiValue::FixedBytes(value) => Token::FixedBytes(value.clone()),
+        AbiValue::Array(values) => Token::Array(tokens(values)?),
+        AbiValue::FixedArray(values) => Token::FixedArray(tokens(values)?),
+        AbiValue::Tuple(values) => Token::Tuple(tokens(values)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_job_ids_wider_than_64_bits() {
+        let event = parse_event("event NewJob(uint256 job_id)").unwrap();
+        let job_id = (U256::one() << 200) + 42;
+        let mut data = [0u8; 32];
+        job_id.to_big_endian(&mut data);
+        let log = LogEntry {
+            transactionHash: Some(format!("0x{:064x}", 1)),
+            blockNumber: Some(100),
+            data: format!("0x{}", hex::encode(data)),
+            blockHash: Some(format!("0x{:064x}", 100)),
+            transactionIndex: Some(0),
+            topics: vec![topic0(&event)],
+            address: "0x5FbDB2315678afecb367f032d93F642f64180aa3".to_string(),
+            logIndex: Some(0),
+            removed: false,
+        };
+
+        let decoded = decode_log(&event, &log).unwrap();
+        assert_eq!(decoded.signature, "NewJob(uint256)");
+        assert_eq!(decoded.uint("job_id"), Ok(job_id));
+        // The value survives the way back into a callback argument
+        let value = decoded.param("job_id").unwrap();
+        assert_eq!(value_to_token(value), Ok(Token::Uint(job_id)));
+    }
+}
@@ -1,9 +1,56 @@
 use candid::{self, CandidType, Deserialize, Principal};
 use ic_cdk::{self, api::call::CallResult};
 
-pub const CANISTER_ID: Principal =
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::future::Future;
+
+use crate::error::CoprocessorError;
+use crate::memory::{Chain, ConsensusPolicy, CONFIG};
+use crate::{chains, rpc_backend, runtime};
+
+/// The EVM RPC canister deployed on the IC, used unless another one is configured.
+pub const DEFAULT_CANISTER_ID: Principal =
     Principal::from_slice(b"\x00\x00\x00\x00\x02\x30\x00\xCC\x01\x01"); // 7hfb6-caaaa-aaaar-qadga-cai
 
+/// Upper bound of the response size of an `eth_estimateGas` request, including a revert reason.
+const ESTIMATE_GAS_RESPONSE_SIZE: u64 = 1024;
+
+/// The largest response an HTTPS outcall can receive.
+const MAX_RESPONSE_SIZE: u64 = 2_000_000;
+
+/// Number of providers the EVM RPC canister queries if none are specified.
+const DEFAULT_PROVIDER_COUNT: u128 = 3;
+
+/// Added to the cycles that the EVM RPC canister reports as the cost of a call, in percent.
+/// Unused cycles are refunded.
+const CYCLES_MARGIN_PERCENT: u128 = 20;
+
+/// Number of times a call is retried with more cycles or a larger response size estimate.
+const MAX_CALL_RETRIES: usize = 2;
+
+/// The cost of a call grows with the size of its request. Costs are cached for requests of up
+/// to a multiple of this many bytes, e.g. for raw transactions with differing calldata.
+const REQUEST_SIZE_BUCKET: usize = 1024;
+
+thread_local! {
+    /// The response size estimate and cost of the calls to a chain, by chain ID, method and
+    /// request size bucket. The estimates start at `default_response_size` and grow whenever a
+    /// response is larger.
+    static CALL_COSTS: RefCell<BTreeMap<(u64, String, usize), CallCost>> = RefCell::default();
+
+    static NEXT_REQUEST_ID: Cell<u64> = const { Cell::new(1) };
+}
+
+#[derive(Clone, Copy)]
+struct CallCost {
+    response_size: u64,
+    cycles: u128,
+}
+
 #[derive(CandidType, Deserialize)]
 pub enum Auth {
     RegisterProvider,
@@ -12,7 +59,7 @@ pub enum Auth {
     Manage,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone, Debug)]
 pub enum EthSepoliaService {
     Alchemy,
     BlockPi,
@@ -20,19 +67,19 @@ pub enum EthSepoliaService {
     Ankr,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct HttpHeader {
     pub value: String,
     pub name: String,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct RpcApi {
     pub url: String,
     pub headers: Option<Vec<HttpHeader>>,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone, Debug)]
 pub enum EthMainnetService {
     Alchemy,
     BlockPi,
@@ -41,7 +88,7 @@ pub enum EthMainnetService {
     Ankr,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone, Debug)]
 pub enum RpcServices {
     EthSepolia(Option<Vec<EthSepoliaService>>),
     Custom { chainId: u64, services: Vec<RpcApi> },
@@ -53,7 +100,7 @@ pub struct RpcConfig {
     pub responseSizeEstimate: Option<u64>,
 }
 
-#[derive(CandidType, Deserialize, Debug)]
+#[derive(CandidType, Deserialize, Clone, Debug)]
 pub enum BlockTag {
     Earliest,
     Safe,
@@ -63,14 +110,14 @@ pub enum BlockTag {
     Pending,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone)]
 pub struct FeeHistoryArgs {
     pub blockCount: u128,
     pub newestBlock: BlockTag,
     pub rewardPercentiles: Option<serde_bytes::ByteBuf>,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
 pub struct FeeHistory {
     pub reward: Vec<Vec<u128>>,
     pub gasUsedRatio: Vec<f64>,
@@ -78,13 +125,23 @@ pub struct FeeHistory {
     pub baseFeePerGas: Vec<u128>,
 }
 
-#[derive(CandidType, Deserialize, Debug)]
+/// The envelope of a JSON-RPC response. `result` is null if it is missing.
+#[derive(Deserialize)]
+struct JsonRpcResponse {
+    #[serde(default)]
+    id: serde_json::Value,
+    #[serde(default)]
+    result: serde_json::Value,
+    error: Option<JsonRpcError>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct JsonRpcError {
     pub code: i64,
     pub message: String,
 }
 
-#[derive(CandidType, Deserialize, Debug)]
+#[derive(CandidType, Deserialize, Clone, Debug)]
 pub enum ProviderError {
     TooFewCycles { expected: u128, received: u128 },
     MissingRequiredProvider,
@@ -92,7 +149,7 @@ pub enum ProviderError {
     NoPermission,
 }
 
-#[derive(CandidType, Deserialize, Debug)]
+#[derive(CandidType, Deserialize, Clone, Debug)]
 pub enum ValidationError {
     CredentialPathNotAllowed,
     HostNotAllowed(String),
@@ -102,7 +159,7 @@ pub enum ValidationError {
     InvalidHex(String),
 }
 
-#[derive(CandidType, Deserialize, Debug)]
+#[derive(CandidType, Deserialize, Clone, Debug)]
 pub enum RejectionCode {
     NoError,
     CanisterError,
@@ -113,7 +170,7 @@ pub enum RejectionCode {
     CanisterReject,
 }
 
-#[derive(CandidType, Deserialize, Debug)]
+#[derive(CandidType, Deserialize, Clone, Debug)]
 pub enum HttpOutcallError {
     IcError {
         code: RejectionCode,
@@ -126,7 +183,7 @@ pub enum HttpOutcallError {
     },
 }
 
-#[derive(CandidType, Deserialize, Debug)]
+#[derive(CandidType, Deserialize, Clone, Debug)]
 pub enum RpcError {
     JsonRpcError(JsonRpcError),
     ProviderError(ProviderError),
@@ -140,7 +197,7 @@ pub enum FeeHistoryResult {
     Err(RpcError),
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone, Debug)]
 pub enum RpcService {
     EthSepolia(EthSepoliaService),
     Custom(RpcApi),
@@ -155,7 +212,7 @@ pub enum MultiFeeHistoryResult {
     Inconsistent(Vec<(RpcService, FeeHistoryResult)>),
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, PartialEq)]
 pub struct Block {
     pub miner: String,
     pub totalDifficulty: u128,
@@ -192,7 +249,7 @@ pub enum MultiGetBlockByNumberResult {
     Inconsistent(Vec<(RpcService, GetBlockByNumberResult)>),
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone)]
 pub struct GetLogsArgs {
     pub fromBlock: Option<BlockTag>,
     pub toBlock: Option<BlockTag>,
@@ -200,7 +257,7 @@ pub struct GetLogsArgs {
     pub topics: Option<Vec<Vec<String>>>,
 }
 
-#[derive(CandidType, Deserialize, Debug)]
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
 pub struct LogEntry {
     pub transactionHash: Option<String>,
     pub blockNumber: Option<u128>,
@@ -225,7 +282,7 @@ pub enum MultiGetLogsResult {
     Inconsistent(Vec<(RpcService, GetLogsResult)>),
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone)]
 pub struct GetTransactionCountArgs {
     pub address: String,
     pub block: BlockTag,
@@ -243,7 +300,7 @@ pub enum MultiGetTransactionCountResult {
     Inconsistent(Vec<(RpcService, GetTransactionCountResult)>),
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
 pub struct TransactionReceipt {
     pub to: String,
     pub status: u128,
@@ -272,7 +329,7 @@ pub enum MultiGetTransactionReceiptResult {
     Inconsistent(Vec<(RpcService, GetTransactionReceiptResult)>),
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
 pub enum SendRawTransactionStatus {
     Ok,
     NonceTooLow,
@@ -355,6 +412,15 @@ pub struct UpdateProviderArgs {
     pub providerId: u64,
 }
 
+/// The EVM RPC canister the calls are sent to, see `InitArgs`.
+pub fn canister_id() -> Principal {
+    CONFIG
+        .with(|config| config.borrow().get().evm_rpc_canister)
+        .unwrap_or(DEFAULT_CANISTER_ID)
+}
+
+/// Typed bindings of the methods of the EVM RPC canister. The functions below add cycles,
+/// consensus and error handling on top of them.
 pub struct EvmRpcCanister;
 impl EvmRpcCanister {
     pub async fn get_logs(
@@ -364,7 +430,7 @@ impl EvmRpcCanister {
         cycles: u128,
     ) -> CallResult<(MultiGetLogsResult,)> {
         ic_cdk::api::call::call_with_payment128(
-            CANISTER_ID,
+            canister_id(),
             "eth_getLogs",
             (services, config, args),
             cycles,
@@ -379,7 +445,7 @@ impl EvmRpcCanister {
         cycles: u128,
     ) -> CallResult<(MultiFeeHistoryResult,)> {
         ic_cdk::api::call::call_with_payment128(
-            CANISTER_ID,
+            canister_id(),
             "eth_feeHistory",
             (services, config, args),
             cycles,
@@ -387,6 +453,79 @@ impl EvmRpcCanister {
         .await
     }
 
+    pub async fn eth_get_block_by_number(
+        services: RpcServices,
+        config: Option<RpcConfig>,
+        block: BlockTag,
+        cycles: u128,
+    ) -> CallResult<(MultiGetBlockByNumberResult,)> {
+        ic_cdk::api::call::call_with_payment128(
+            canister_id(),
+            "eth_getBlockByNumber",
+            (services, config, block),
+            cycles,
+        )
+        .await
+    }
+
+    pub async fn eth_get_transaction_count(
+        services: RpcServices,
+        config: Option<RpcConfig>,
+        args: GetTransactionCountArgs,
+        cycles: u128,
+    ) -> CallResult<(MultiGetTransactionCountResult,)> {
+        ic_cdk::api::call::call_with_payment128(
+            canister_id(),
+            "eth_getTransactionCount",
+            (services, config, args),
+            cycles,
+        )
+        .await
+    }
+
+    pub async fn eth_get_transaction_receipt(
+        services: RpcServices,
+        config: Option<RpcConfig>,
+        tx_hash: String,
+        cycles: u128,
+    ) -> CallResult<(MultiGetTransactionReceiptResult,)> {
+        ic_cdk::api::call::call_with_payment128(
+            canister_id(),
+            "eth_getTransactionReceipt",
+            (services, config, tx_hash),
+            cycles,
+        )
+        .await
+    }
+
+    pub async fn request(
+        service: RpcService,
+        json: String,
+        max_response_bytes: u64,
+        cycles: u128,
+    ) -> CallResult<(RequestResult,)> {
+        ic_cdk::api::call::call_with_payment128(
+            canister_id(),
+            "request",
+            (service, json, max_response_bytes),
+            cycles,
+        )
+        .await
+    }
+
+    pub async fn request_cost(
+        service: RpcService,
+        json: String,
+        max_response_bytes: u64,
+    ) -> CallResult<(RequestCostResult,)> {
+        ic_cdk::call(
+            canister_id(),
+            "requestCost",
+            (service, json, max_response_bytes),
+        )
+        .await
+    }
+
     pub async fn eth_send_raw_transaction(
         services: RpcServices,
         config: Option<RpcConfig>,
@@ -394,7 +533,7 @@ impl EvmRpcCanister {
         cycles: u128,
     ) -> CallResult<(MultiSendRawTransactionResult,)> {
         ic_cdk::api::call::call_with_payment128(
-            CANISTER_ID,
+            canister_id(),
             "eth_sendRawTransaction",
             (services, config, raw_tx),
             cycles,
@@ -403,107 +542,691 @@ impl EvmRpcCanister {
     }
 }
 
-//TODO: FIX inconsistency with topic type
 pub async fn get_logs(
-    network: String,
+    chain: &Chain,
     addresses: Vec<String>,
     topics: Option<Vec<Vec<String>>>,
     from_block: u128,
     to_block: BlockTag,
-) -> Vec<LogEntry> {
-    ic_cdk::print(format!(
+) -> Result<Vec<LogEntry>, CoprocessorError> {
+    runtime::print(format!(
         "Getting logs from block {} to block {:?}",
         from_block, to_block
     ));
 
-    let config = None;
-    let args = GetLogsArgs {
+    let params = serde_json::json!([{
+        "address": addresses,
+        "topics": topics,
+        "fromBlock": block_tag_param(&BlockTag::Number(from_block)),
+        "toBlock": block_tag_param(&to_block),
+    }]);
+    let args = &GetLogsArgs {
         addresses,
         fromBlock: Some(BlockTag::Number(from_block)),
         toBlock: Some(to_block),
-        topics: None,
+        topics,
     };
 
-    let services = match network.as_str() {
-        "EthSepolia" => RpcServices::EthSepolia(Some(vec![EthSepoliaService::Alchemy])),
-        "EthMainnet" => RpcServices::EthMainnet(None),
-        _ => RpcServices::EthSepolia(None),
-    };
+    let services = &chain.rpc_services();
 
-    let cycles = 10000000;
-    match EvmRpcCanister::get_logs(services, config, args, cycles).await {
-        Ok((res,)) => match res {
-            MultiGetLogsResult::Consistent(logs) => match logs {
-                GetLogsResult::Ok(logs) => logs,
-                GetLogsResult::Err(e) => {
-                    ic_cdk::trap(format!("Error: {:?}", e).as_str());
+    call_with_cycles(
+        chain,
+        "eth_getLogs",
+        params,
+        |response_size, cycles| async move {
+            let config = rpc_config(response_size);
+            let (result,) = rpc_backend::backend(chain)
+                .get_logs(services.clone(), config, args.clone(), cycles)
+                .await?;
+            match result {
+                MultiGetLogsResult::Consistent(GetLogsResult::Ok(logs)) => Ok(logs),
+                MultiGetLogsResult::Consistent(GetLogsResult::Err(e)) => Err(e.into()),
+                MultiGetLogsResult::Inconsistent(results) => {
+                    resolve("eth_getLogs", &chain.consensus().reads, results)
                 }
-            },
-            MultiGetLogsResult::Inconsistent(_) => {
-                ic_cdk::trap("Logs are inconsistent");
             }
         },
-        Err(e) => ic_cdk::trap(format!("Error: {:?}", e).as_str()),
-    }
+    )
+    .await
 }
 
 pub async fn fee_history(
-    network: String,
+    chain: &Chain,
     block_count: u128,
     newest_block: BlockTag,
     reward_percentiles: Option<serde_bytes::ByteBuf>,
-) -> FeeHistory {
-    let config = None;
-    let args = FeeHistoryArgs {
+) -> Result<FeeHistory, CoprocessorError> {
+    let params = serde_json::json!([
+        format!("{:#x}", block_count),
+        block_tag_param(&newest_block),
+        reward_percentiles
+            .as_ref()
+            .map(|percentiles| percentiles.to_vec()),
+    ]);
+    let args = &FeeHistoryArgs {
         blockCount: block_count,
         newestBlock: newest_block,
         rewardPercentiles: reward_percentiles,
     };
 
-    let services = match network.as_str() {
-        "EthSepolia" => RpcServices::EthSepolia(Some(vec![EthSepoliaService::Alchemy])),
-        "EthMainnet" => RpcServices::EthMainnet(None),
-        _ => RpcServices::EthSepolia(None),
-    };
+    let services = &chain.rpc_services();
 
-    let cycles = 10000000;
-    match EvmRpcCanister::eth_fee_history(services, config, args, cycles).await {
-        Ok((res,)) => match res {
-            MultiFeeHistoryResult::Consistent(fee_history) => match fee_history {
-                FeeHistoryResult::Ok(fee_history) => fee_history.unwrap(),
-                FeeHistoryResult::Err(e) => {
-                    ic_cdk::trap(format!("Error: {:?}", e).as_str());
+    let fee_history = call_with_cycles(
+        chain,
+        "eth_feeHistory",
+        params,
+        |response_size, cycles| async move {
+            let config = rpc_config(response_size);
+            let (result,) = rpc_backend::backend(chain)
+                .eth_fee_history(services.clone(), config, args.clone(), cycles)
+                .await?;
+            match result {
+                MultiFeeHistoryResult::Consistent(FeeHistoryResult::Ok(fee_history)) => {
+                    Ok(fee_history)
+                }
+                MultiFeeHistoryResult::Consistent(FeeHistoryResult::Err(e)) => Err(e.into()),
+                MultiFeeHistoryResult::Inconsistent(results) => {
+                    resolve("eth_feeHistory", &chain.consensus().reads, results)
                 }
-            },
-            MultiFeeHistoryResult::Inconsistent(_) => {
-                ic_cdk::trap("Fee history is inconsistent");
             }
         },
-        Err(e) => ic_cdk::trap(format!("Error: {:?}", e).as_str()),
-    }
+    )
+    .await?;
+    fee_history.ok_or_else(|| CoprocessorError::InvalidResponse("Empty fee history".to_string()))
+}
+
+/// Requests a block, without its transactions, by number or tag.
+pub async fn get_block_by_number(
+    chain: &Chain,
+    block: BlockTag,
+) -> Result<Block, CoprocessorError> {
+    let params = serde_json::json!([block_tag_param(&block), false]);
+    let block = &block;
+
+    let services = &chain.rpc_services();
+
+    call_with_cycles(
+        chain,
+        "eth_getBlockByNumber",
+        params,
+        |response_size, cycles| async move {
+            let config = rpc_config(response_size);
+            let (result,) = rpc_backend::backend(chain)
+                .eth_get_block_by_number(services.clone(), config, block.clone(), cycles)
+                .await?;
+            match result {
+                MultiGetBlockByNumberResult::Consistent(GetBlockByNumberResult::Ok(block)) => {
+                    Ok(block)
+                }
+                MultiGetBlockByNumberResult::Consistent(GetBlockByNumberResult::Err(e)) => {
+                    Err(e.into())
+                }
+                MultiGetBlockByNumberResult::Inconsistent(results) => {
+                    resolve("eth_getBlockByNumber", &chain.consensus().reads, results)
+                }
+            }
+        },
+    )
+    .await
+}
+
+/// Requests the receipt of a transaction. Returns `None` while the transaction is pending or if
+/// it is unknown to the providers.
+pub async fn get_transaction_receipt(
+    chain: &Chain,
+    tx_hash: String,
+) -> Result<Option<TransactionReceipt>, CoprocessorError> {
+    let params = serde_json::json!([tx_hash]);
+    let tx_hash = &tx_hash;
+
+    let services = &chain.rpc_services();
+
+    call_with_cycles(
+        chain,
+        "eth_getTransactionReceipt",
+        params,
+        |response_size, cycles| async move {
+            let config = rpc_config(response_size);
+            let (result,) = rpc_backend::backend(chain)
+                .eth_get_transaction_receipt(services.clone(), config, tx_hash.clone(), cycles)
+                .await?;
+            match result {
+                MultiGetTransactionReceiptResult::Consistent(GetTransactionReceiptResult::Ok(
+                    receipt,
+                )) => Ok(receipt),
+                MultiGetTransactionReceiptResult::Consistent(GetTransactionReceiptResult::Err(
+                    e,
+                )) => Err(e.into()),
+                MultiGetTransactionReceiptResult::Inconsistent(results) => resolve(
+                    "eth_getTransactionReceipt",
+                    &chain.consensus().reads,
+                    results,
+                ),
+            }
+        },
+    )
+    .await
 }
 
-pub async fn send_raw_transaction(network: String, raw_tx: String) -> SendRawTransactionStatus {
-    let config = None;
-    let services = match network.as_str() {
-        "EthSepolia" => RpcServices::EthSepolia(Some(vec![EthSepoliaService::Alchemy])),
-        "EthMainnet" => RpcServices::EthMainnet(None),
-        _ => RpcServices::EthSepolia(None),
+/// Requests the number of transactions sent from an address as of a block, i.e. the nonce of
+/// its next transaction.
+pub async fn get_transaction_count(
+    chain: &Chain,
+    address: String,
+    block: BlockTag,
+) -> Result<u128, CoprocessorError> {
+    let params = serde_json::json!([address, block_tag_param(&block)]);
+    let args = &GetTransactionCountArgs { address, block };
+
+    let services = &chain.rpc_services();
+
+    call_with_cycles(
+        chain,
+        "eth_getTransactionCount",
+        params,
+        |response_size, cycles| async move {
+            let config = rpc_config(response_size);
+            let (result,) = rpc_backend::backend(chain)
+                .eth_get_transaction_count(services.clone(), config, args.clone(), cycles)
+                .await?;
+            match result {
+                MultiGetTransactionCountResult::Consistent(GetTransactionCountResult::Ok(
+                    count,
+                )) => Ok(count),
+                MultiGetTransactionCountResult::Consistent(GetTransactionCountResult::Err(e)) => {
+                    Err(e.into())
+                }
+                MultiGetTransactionCountResult::Inconsistent(results) => {
+                    resolve("eth_getTransactionCount", &chain.consensus().reads, results)
+                }
+            }
+        },
+    )
+    .await
+}
+
+/// Estimates the gas used by a transaction with `eth_estimateGas`. Fails with a `JsonRpcError`
+/// if the transaction would revert.
+pub async fn estimate_gas(
+    chain: &Chain,
+    from: String,
+    to: String,
+    data: &[u8],
+) -> Result<u128, CoprocessorError> {
+    let params = [serde_json::json!({
+        "from": from,
+        "to": to,
+        "data": format!("0x{}", hex::encode(data)),
+    })];
+    let gas: String = rpc_request(chain, "eth_estimateGas", params).await?;
+    parse_quantity(&gas)
+}
+
+/// Executes a call of a contract with `eth_call` against the state as of `block`, without
+/// sending a transaction, and returns the return data.
+pub async fn call(
+    chain: &Chain,
+    to: String,
+    data: &[u8],
+    block: BlockTag,
+) -> Result<Vec<u8>, CoprocessorError> {
+    let transaction = serde_json::json!({
+        "to": to,
+        "data": format!("0x{}", hex::encode(data)),
+    });
+    let output: String =
+        rpc_request(chain, "eth_call", (transaction, block_tag_param(&block))).await?;
+    hex::decode(output.trim_start_matches("0x")).map_err(|e| {
+        CoprocessorError::InvalidResponse(format!("Invalid return data {}: {}", output, e))
+    })
+}
+
+/// Sends a JSON-RPC request to the first provider of the chain, via the `request` method of the
+/// EVM RPC canister, and decodes its result. Unlike the methods above, the request is not
+/// replicated across providers.
+///
+/// An error object in the response is returned as `JsonRpcError`. A response to a different
+/// request, or a result that cannot be decoded as `R`, is an `InvalidResponse`.
+pub async fn rpc_request<P: Serialize, R: DeserializeOwned>(
+    chain: &Chain,
+    method: &str,
+    params: P,
+) -> Result<R, CoprocessorError> {
+    let params = serde_json::to_value(params).map_err(|e| {
+        CoprocessorError::InvalidRequest(format!("Invalid parameters of {}: {}", method, e))
+    })?;
+    let id = NEXT_REQUEST_ID.with(|next| next.replace(next.get().wrapping_add(1)));
+    let request = &request_json(id, method, params.clone());
+    let service = &chain.rpc_service();
+
+    let response = call_with_cycles(chain, method, params, |response_size, cycles| async move {
+        let (result,) = rpc_backend::backend(chain)
+            .request(service.clone(), request.clone(), response_size, cycles)
+            .await?;
+        match result {
+            RequestResult::Ok(response) => Ok(response),
+            RequestResult::Err(e) => Err(e.into()),
+        }
+    })
+    .await?;
+    parse_response(id, method, &response)
+}
+
+/// Decodes the result of the response to the JSON-RPC request with the given `id`, see
+/// `rpc_request`.
+fn parse_response<R: DeserializeOwned>(
+    id: u64,
+    method: &str,
+    response: &str,
+) -> Result<R, CoprocessorError> {
+    let invalid_response = |error: String| {
+        CoprocessorError::InvalidResponse(format!(
+            "{} in response to {}: {}",
+            error, method, response
+        ))
     };
+    let envelope: JsonRpcResponse =
+        serde_json::from_str(response).map_err(|e| invalid_response(e.to_string()))?;
+    if envelope.id != serde_json::json!(id) {
+        return Err(invalid_response(format!(
+            "Expected ID {}, got {}",
+            id, envelope.id
+        )));
+    }
+    if let Some(error) = envelope.error {
+        return Err(CoprocessorError::JsonRpcError(error));
+    }
+    serde_json::from_value(envelope.result).map_err(|e| invalid_response(e.to_string()))
+}
+
+pub async fn send_raw_transaction(
+    chain: &Chain,
+    raw_tx: String,
+) -> Result<SendRawTransactionStatus, CoprocessorError> {
+    let params = serde_json::json!([raw_tx]);
+    let raw_tx = &raw_tx;
 
-    let cycles = 10000000;
-    match EvmRpcCanister::eth_send_raw_transaction(services, config, raw_tx, cycles).await {
-        Ok((res,)) => match res {
-            MultiSendRawTransactionResult::Consistent(status) => match status {
-                SendRawTransactionResult::Ok(status) => status,
-                SendRawTransactionResult::Err(e) => {
-                    ic_cdk::trap(format!("Error: {:?}", e).as_str());
+    let services = &chain.rpc_services();
+
+    call_with_cycles(
+        chain,
+        "eth_sendRawTransaction",
+        params,
+        |response_size, cycles| async move {
+            let config = rpc_config(response_size);
+            let (result,) = rpc_backend::backend(chain)
+                .eth_send_raw_transaction(services.clone(), config, raw_tx.clone(), cycles)
+                .await?;
+            match result {
+                MultiSendRawTransactionResult::Consistent(SendRawTransactionResult::Ok(status)) => {
+                    Ok(status)
+                }
+                MultiSendRawTransactionResult::Consistent(SendRawTransactionResult::Err(e)) => {
+                    Err(e.into())
+                }
+                MultiSendRawTransactionResult::Inconsistent(mut results) => {
+                    // A transaction accepted by any provider has reached the network
+                    results.sort_by_key(|(_, result)| {
+                        !matches!(
+                            result,
+                            SendRawTransactionResult::Ok(SendRawTransactionStatus::Ok)
+                        )
+                    });
+                    resolve(
+                        "eth_sendRawTransaction",
+                        &chain.consensus().send_raw_transaction,
+                        results,
+                    )
                 }
-            },
-            MultiSendRawTransactionResult::Inconsistent(_) => {
-                ic_cdk::trap("Status is inconsistent");
             }
         },
-        Err(e) => ic_cdk::trap(format!("Error: {:?}", e).as_str()),
+    )
+    .await
+}
+
+/// Makes a call to the EVM RPC canister with the cycles it costs.
+///
+/// `call` receives the response size estimate and the cycles attached to the call. If the call
+/// fails because the cycles or the estimate turn out to be too low, it is made again with the
+/// cycles the EVM RPC canister asks for, respectively a doubled estimate. The adjusted values
+/// are used for all further calls of the method to the chain with requests of a similar size.
+async fn call_with_cycles<T, F, Fut>(
+    chain: &Chain,
+    method: &str,
+    params: serde_json::Value,
+    call: F,
+) -> Result<T, CoprocessorError>
+where
+    F: Fn(u64, u128) -> Fut,
+    Fut: Future<Output = Result<T, CoprocessorError>>,
+{
+    // The cost is requested for the largest request of the size bucket, including the longest
+    // ID, so that it covers every request the cached cost is used for. JSON allows padding the
+    // request with whitespace.
+    let request = request_json(u64::MAX, method, params);
+    let bucket = request.len().div_ceil(REQUEST_SIZE_BUCKET);
+    let request = format!("{:width$}", request, width = bucket * REQUEST_SIZE_BUCKET);
+    let key = (chain.network.chain_id(), method.to_string(), bucket);
+
+    let mut cost = match CALL_COSTS.with(|costs| costs.borrow().get(&key).copied()) {
+        Some(cost) => cost,
+        None => {
+            let response_size = default_response_size(method);
+            CallCost {
+                response_size,
+                cycles: request_cost(chain, &request, response_size).await?,
+            }
+        }
+    };
+
+    let mut attempt = 0;
+    let result = loop {
+        let result = call(cost.response_size, cost.cycles).await;
+        if attempt == MAX_CALL_RETRIES {
+            break result;
+        }
+        attempt += 1;
+
+        match &result {
+            Err(CoprocessorError::ProviderError(ProviderError::TooFewCycles {
+                expected, ..
+            })) => {
+                runtime::print(format!(
+                    "{}: retrying with {} cycles instead of {}",
+                    method, expected, cost.cycles
+                ));
+                cost.cycles = with_margin(*expected);
+            }
+            Err(error)
+                if is_response_too_large(error) && cost.response_size < MAX_RESPONSE_SIZE =>
+            {
+                cost.response_size = (cost.response_size * 2).min(MAX_RESPONSE_SIZE);
+                runtime::print(format!(
+                    "{}: retrying with a response size estimate of {} bytes",
+                    method, cost.response_size
+                ));
+                cost.cycles = request_cost(chain, &request, cost.response_size).await?;
+            }
+            _ => break result,
+        }
+    };
+
+    CALL_COSTS.with(|costs| costs.borrow_mut().insert(key, cost));
+    result
+}
+
+/// Asks the EVM RPC canister for the cycles a JSON-RPC request costs with all providers of the
+/// chain.
+async fn request_cost(
+    chain: &Chain,
+    request: &str,
+    response_size: u64,
+) -> Result<u128, CoprocessorError> {
+    let (result,) = rpc_backend::backend(chain)
+        .request_cost(chain.rpc_service(), request.to_string(), response_size)
+        .await?;
+    let cycles = match result {
+        RequestCostResult::Ok(cycles) => cycles,
+        RequestCostResult::Err(e) => return Err(e.into()),
+    };
+    let providers = match chain.rpc_services() {
+        RpcServices::EthMainnet(Some(services)) => services.len() as u128,
+        RpcServices::EthSepolia(Some(services)) => services.len() as u128,
+        RpcServices::Custom { services, .. } => services.len() as u128,
+        RpcServices::EthMainnet(None) | RpcServices::EthSepolia(None) => DEFAULT_PROVIDER_COUNT,
+    };
+    Ok(with_margin(cycles.saturating_mul(providers.max(1))))
+}
+
+/// The initial response size estimate of a method, in bytes.
+fn default_response_size(method: &str) -> u64 {
+    match method {
+        "eth_getLogs" => 100_000,
+        // Blocks are requested without their transactions, but including their hashes
+        "eth_getBlockByNumber" => 24_000,
+        "eth_getTransactionReceipt" => 8_000,
+        "eth_feeHistory" => 4_000,
+        "eth_estimateGas" => ESTIMATE_GAS_RESPONSE_SIZE,
+        _ => 1_000,
+    }
+}
+
+/// Returns true if the response of a call exceeded its response size estimate.
+fn is_response_too_large(error: &CoprocessorError) -> bool {
+    match error {
+        CoprocessorError::HttpOutcallError(HttpOutcallError::IcError { message, .. }) => {
+            message.contains("size limit") || message.contains("max_response_bytes")
+        }
+        _ => false,
+    }
+}
+
+fn with_margin(cycles: u128) -> u128 {
+    cycles.saturating_mul(100 + CYCLES_MARGIN_PERCENT) / 100
+}
+
+fn rpc_config(response_size: u64) -> Option<RpcConfig> {
+    Some(RpcConfig {
+        responseSizeEstimate: Some(response_size),
+    })
+}
+
+fn request_json(id: u64, method: &str, params: serde_json::Value) -> String {
+    serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "method": method,
+        "params": params,
+    })
+    .to_string()
+}
+
+/// Parses a hexadecimal JSON-RPC quantity.
+fn parse_quantity(quantity: &str) -> Result<u128, CoprocessorError> {
+    u128::from_str_radix(quantity.trim_start_matches("0x"), 16).map_err(|e| {
+        CoprocessorError::InvalidResponse(format!("Invalid quantity {}: {}", quantity, e))
+    })
+}
+
+/// The JSON-RPC representation of a block tag.
+pub fn block_tag_param(block: &BlockTag) -> String {
+    match block {
+        BlockTag::Earliest => "earliest".to_string(),
+        BlockTag::Safe => "safe".to_string(),
+        BlockTag::Finalized => "finalized".to_string(),
+        BlockTag::Latest => "latest".to_string(),
+        BlockTag::Number(number) => format!("{:#x}", number),
+        BlockTag::Pending => "pending".to_string(),
+    }
+}
+
+/// Identifies a provider in the logs. Custom providers are identified by their redacted URL, as
+/// their path, query and headers usually contain API keys.
+fn provider_label(service: &RpcService) -> String {
+    match service {
+        RpcService::Custom(api) => chains::redact_url(&api.url),
+        RpcService::EthSepolia(service) => format!("EthSepolia({:?})", service),
+        RpcService::EthMainnet(service) => format!("EthMainnet({:?})", service),
+        RpcService::Chain(id) => format!("Chain({})", id),
+        RpcService::Provider(id) => format!("Provider({})", id),
+    }
+}
+
+/// Combines the differing responses of the providers of a replicated call according to
+/// `policy`. The providers whose response is not used are logged.
+fn resolve<T: PartialEq, R: Into<Result<T, RpcError>>>(
+    method: &str,
+    policy: &ConsensusPolicy,
+    results: Vec<(RpcService, R)>,
+) -> Result<T, CoprocessorError> {
+    // Providers returning the same successful response are grouped, in the order of the
+    // providers
+    let mut groups: Vec<(T, Vec<String>)> = Vec::new();
+    let mut errors: Vec<(String, RpcError)> = Vec::new();
+    for (service, result) in results {
+        let service = provider_label(&service);
+        match result.into() {
+            Ok(value) => match groups.iter_mut().find(|(other, _)| *other == value) {
+                Some((_, services)) => services.push(service),
+                None => groups.push((value, vec![service])),
+            },
+            Err(error) => errors.push((service, error)),
+        }
+    }
+
+    let chosen = match policy {
+        ConsensusPolicy::Unanimous => None,
+        ConsensusPolicy::Majority { min } => {
+            let count = |index: usize| groups[index].1.len();
+            (0..groups.len())
+                .max_by_key(|index| count(*index))
+                .filter(|best| {
+                    count(*best) >= *min as usize
+                        && (0..groups.len())
+                            .all(|other| other == *best || count(other) < count(*best))
+                })
+        }
+        ConsensusPolicy::FirstSuccess => (!groups.is_empty()).then_some(0),
+    };
+
+    match chosen {
+        Some(index) => {
+            let (value, agreeing) = groups.swap_remove(index);
+            for (_, services) in groups {
+                runtime::print(format!(
+                    "{}: providers {:?} disagree with {:?}",
+                    method, services, agreeing
+                ));
+            }
+            for (service, error) in errors {
+                runtime::print(format!(
+                    "{}: provider {:?} failed: {:?}",
+                    method, service, error
+                ));
+            }
+            Ok(value)
+        }
+        None => {
+            for (index, (_, services)) in groups.iter().enumerate() {
+                runtime::print(format!(
+                    "{}: response {} returned by providers {:?}",
+                    method,
+                    index + 1,
+                    services
+                ));
+            }
+            for (service, error) in errors.iter() {
+                runtime::print(format!(
+                    "{}: provider {:?} failed: {:?}",
+                    method, service, error
+                ));
+            }
+            // If no provider succeeded, there is nothing to disagree about
+            match (groups.is_empty(), errors.into_iter().next()) {
+                (true, Some((_, error))) => Err(error.into()),
+                _ => Err(CoprocessorError::InconsistentResponses {
+                    method: method.to_string(),
+                }),
+            }
+        }
+    }
+}
+
+impl From<GetLogsResult> for Result<Vec<LogEntry>, RpcError> {
+    fn from(result: GetLogsResult) -> Self {
+        match result {
+            GetLogsResult::Ok(logs) => Ok(logs),
+            GetLogsResult::Err(e) => Err(e),
+        }
+    }
+}
+
+impl From<FeeHistoryResult> for Result<Option<FeeHistory>, RpcError> {
+    fn from(result: FeeHistoryResult) -> Self {
+        match result {
+            FeeHistoryResult::Ok(fee_history) => Ok(fee_history),
+            FeeHistoryResult::Err(e) => Err(e),
+        }
+    }
+}
+
+impl From<GetBlockByNumberResult> for Result<Block, RpcError> {
+    fn from(result: GetBlockByNumberResult) -> Self {
+        match result {
+            GetBlockByNumberResult::Ok(block) => Ok(block),
+            GetBlockByNumberResult::Err(e) => Err(e),
+        }
+    }
+}
+
+impl From<GetTransactionReceiptResult> for Result<Option<TransactionReceipt>, RpcError> {
+    fn from(result: GetTransactionReceiptResult) -> Self {
+        match result {
+            GetTransactionReceiptResult::Ok(receipt) => Ok(receipt),
+            GetTransactionReceiptResult::Err(e) => Err(e),
+        }
+    }
+}
+
+impl From<GetTransactionCountResult> for Result<u128, RpcError> {
+    fn from(result: GetTransactionCountResult) -> Self {
+        match result {
+            GetTransactionCountResult::Ok(count) => Ok(count),
+            GetTransactionCountResult::Err(e) => Err(e),
+        }
+    }
+}
+
+impl From<SendRawTransactionResult> for Result<SendRawTransactionStatus, RpcError> {
+    fn from(result: SendRawTransactionResult) -> Self {
+        match result {
+            SendRawTransactionResult::Ok(status) => Ok(status),
+            SendRawTransactionResult::Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_response_decodes_the_result() {
+        let response = r#"{"jsonrpc":"2.0","id":7,"result":"0x2a"}"#;
+        assert_eq!(
+            parse_response::<String>(7, "eth_call", response).unwrap(),
+            "0x2a"
+        );
+    }
+
+    #[test]
+    fn parse_response_rejects_the_response_to_another_request() {
+        let response = r#"{"jsonrpc":"2.0","id":8,"result":"0x2a"}"#;
+        match parse_response::<String>(7, "eth_call", response) {
+            Err(CoprocessorError::InvalidResponse(message)) => {
+                assert!(message.starts_with("Expected ID 7, got 8 in response to eth_call"))
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_response_returns_the_error_object() {
+        let response = r#"{"jsonrpc":"2.0","id":7,"error":{"code":3,"message":"execution reverted","data":"0x08c379a0"}}"#;
+        match parse_response::<String>(7, "eth_call", response) {
+            Err(CoprocessorError::JsonRpcError(JsonRpcError { code, message })) => {
+                assert_eq!((code, message.as_str()), (3, "execution reverted"))
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_response_rejects_undecodable_results() {
+        let response = r#"{"jsonrpc":"2.0","id":7,"result":{"balance":"0x1"}}"#;
+        assert!(matches!(
+            parse_response::<String>(7, "eth_call", response),
+            Err(CoprocessorError::InvalidResponse(_))
+        ));
     }
 }
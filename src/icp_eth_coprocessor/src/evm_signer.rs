@@ -5,14 +5,12 @@ use ethers_core::types::transaction::eip1559::Eip1559TransactionRequest;
 use ethers_core::types::{Bytes, Signature};
 use ethers_core::utils::keccak256;
 
-use ic_cdk::api::management_canister::ecdsa::{
-    ecdsa_public_key, sign_with_ecdsa, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgument,
-    SignWithEcdsaArgument,
-};
 use serde::Serialize;
 use std::str::FromStr;
 
+use crate::error::CoprocessorError;
 use crate::memory::STATE;
+use crate::runtime;
 
 #[derive(CandidType, Serialize, Debug)]
 pub struct PublicKeyReply {
@@ -36,21 +34,11 @@ pub struct SignRequest {
     pub data: Option<Vec<u8>>,
 }
 
-pub async fn get_public_key() -> Vec<u8> {
-    let (key,) = ecdsa_public_key(EcdsaPublicKeyArgument {
-        canister_id: None,
-        derivation_path: [].to_vec(),
-        key_id: EcdsaKeyId {
-            curve: EcdsaCurve::Secp256k1,
-            name: "dfx_test_key".to_string(),
-        },
-    })
-    .await
-    .expect("failed to get public key");
-    key.public_key
+pub async fn get_public_key() -> Result<Vec<u8>, CoprocessorError> {
+    runtime::signer().public_key().await
 }
 
-pub async fn sign_transaction(req: SignRequest) -> String {
+pub async fn sign_transaction(req: SignRequest) -> Result<String, CoprocessorError> {
     const EIP1559_TX_ID: u8 = 2;
 
     let data = req.data.as_ref().map(|d| Bytes::from(d.clone()));
@@ -60,7 +48,12 @@ pub async fn sign_transaction(req: SignRequest) -> String {
         from: None,
         to: Some(
             Address::from_str(&req.to)
-                .expect("failed to parse the destination address")
+                .map_err(|e| {
+                    CoprocessorError::InvalidTransaction(format!(
+                        "Invalid destination address {}: {}",
+                        req.to, e
+                    ))
+                })?
                 .into(),
         ),
         gas: Some(req.gas),
@@ -77,19 +70,7 @@ pub async fn sign_transaction(req: SignRequest) -> String {
 
     let txhash = keccak256(&unsigned_tx_bytes);
 
-    let signature = sign_with_ecdsa(SignWithEcdsaArgument {
-        message_hash: txhash.to_vec(),
-        derivation_path: [].to_vec(),
-        key_id: EcdsaKeyId {
-            curve: EcdsaCurve::Secp256k1,
-            // TODO: use the correct key id
-            name: "dfx_test_key".to_string(),
-        },
-    })
-    .await
-    .expect("failed to sign the transaction")
-    .0
-    .signature;
+    let signature = runtime::signer().sign(txhash.to_vec()).await?;
 
     let pubkey = STATE
         .with(|state| {
@@ -97,10 +78,12 @@ pub async fn sign_transaction(req: SignRequest) -> String {
             let s = s.get();
             s.ecdsa_pub_key.clone()
         })
-        .expect("failed to get the public key");
+        .ok_or(CoprocessorError::SigningError(
+            "The public key is not initialized".to_string(),
+        ))?;
 
     let signature = Signature {
-        v: y_parity(&txhash, &signature, &pubkey),
+        v: y_parity(&txhash, &signature, &pubkey)?,
         r: U256::from_big_endian(&signature[0..32]),
         s: U256::from_big_endian(&signature[32..64]),
     };
@@ -108,16 +91,24 @@ pub async fn sign_transaction(req: SignRequest) -> String {
     let mut signed_tx_bytes = tx.rlp_signed(&signature).to_vec();
     signed_tx_bytes.insert(0, EIP1559_TX_ID);
 
-    format!("0x{}", hex::encode(&signed_tx_bytes))
+    Ok(format!("0x{}", hex::encode(&signed_tx_bytes)))
+}
+
+/// Computes the hash of a signed transaction, as returned by `sign_transaction`.
+pub fn transaction_hash(raw_tx: &str) -> String {
+    let bytes =
+        hex::decode(raw_tx.trim_start_matches("0x")).expect("failed to decode the transaction");
+    format!("0x{}", hex::encode(keccak256(bytes)))
 }
 
 /// Converts the public key bytes to an Ethereum address with a checksum.
-pub fn pubkey_bytes_to_address(pubkey_bytes: &[u8]) -> String {
+pub fn pubkey_bytes_to_address(pubkey_bytes: &[u8]) -> Result<String, CoprocessorError> {
     use ethers_core::k256::elliptic_curve::sec1::ToEncodedPoint;
     use ethers_core::k256::PublicKey;
 
-    let key =
-        PublicKey::from_sec1_bytes(pubkey_bytes).expect("failed to parse the public key as SEC1");
+    let key = PublicKey::from_sec1_bytes(pubkey_bytes).map_err(|e| {
+        CoprocessorError::SigningError(format!("Failed to parse the public key as SEC1: {}", e))
+    })?;
     let point = key.to_encoded_point(false);
     // we re-encode the key to the decompressed representation.
     let point_bytes = point.as_bytes();
@@ -125,27 +116,34 @@ pub fn pubkey_bytes_to_address(pubkey_bytes: &[u8]) -> String {
 
     let hash = keccak256(&point_bytes[1..]);
 
-    ethers_core::utils::to_checksum(&Address::from_slice(&hash[12..32]), None)
+    Ok(ethers_core::utils::to_checksum(
+        &Address::from_slice(&hash[12..32]),
+        None,
+    ))
 }
 
 /// Computes the parity bit allowing to recover the public key from the signature.
-fn y_parity(prehash: &[u8], sig: &[u8], pubkey: &[u8]) -> u64 {
+fn y_parity(prehash: &[u8], sig: &[u8], pubkey: &[u8]) -> Result<u64, CoprocessorError> {
     use ethers_core::k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
 
-    let orig_key = VerifyingKey::from_sec1_bytes(pubkey).expect("failed to parse the pubkey");
-    let signature = Signature::try_from(sig).unwrap();
+    let signing_error = |e: ethers_core::k256::ecdsa::Error| {
+        CoprocessorError::SigningError(format!("Invalid signature or public key: {}", e))
+    };
+
+    let orig_key = VerifyingKey::from_sec1_bytes(pubkey).map_err(signing_error)?;
+    let signature = Signature::try_from(sig).map_err(signing_error)?;
     for parity in [0u8, 1] {
-        let recid = RecoveryId::try_from(parity).unwrap();
+        let recid = RecoveryId::try_from(parity).map_err(signing_error)?;
         let recovered_key = VerifyingKey::recover_from_prehash(prehash, &signature, recid)
-            .expect("failed to recover key");
+            .map_err(signing_error)?;
         if recovered_key == orig_key {
-            return parity as u64;
+            return Ok(parity as u64);
         }
     }
 
-    panic!(
-        "failed to recover the parity bit from a signature; sig: {}, pubkey: {}",
+    Err(CoprocessorError::SigningError(format!(
+        "Failed to recover the parity bit from a signature; sig: {}, pubkey: {}",
         hex::encode(sig),
         hex::encode(pubkey)
-    )
-}
\ No newline at end of file
+    )))
+}
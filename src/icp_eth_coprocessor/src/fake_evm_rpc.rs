@@ -0,0 +1,366 @@
+use ic_cdk::api::call::CallResult;
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::evm_rpc::{
+    Block, BlockTag, FeeHistory, FeeHistoryArgs, FeeHistoryResult, GetBlockByNumberResult,
+    GetLogsArgs, GetLogsResult, GetTransactionCountArgs, GetTransactionCountResult,
+    GetTransactionReceiptResult, LogEntry, MultiFeeHistoryResult, MultiGetBlockByNumberResult,
+    MultiGetLogsResult, MultiGetTransactionCountResult, MultiGetTransactionReceiptResult,
+    MultiSendRawTransactionResult, RequestCostResult, RequestResult, RpcConfig, RpcError,
+    RpcService, RpcServices, SendRawTransactionResult, SendRawTransactionStatus,
+    TransactionReceipt,
+};
+use crate::rpc_backend::EvmRpcBackend;
+use crate::runtime::BoxFuture;
+
+/// The cycles `requestCost` reports for any request.
+const REQUEST_COST: u128 = 1_000_000;
+
+/// An in-memory EVM RPC canister whose chain is scripted by the test. All providers agree, so
+/// every result is `Consistent`. Install it with `rpc_backend::set_backend`.
+#[derive(Default)]
+pub struct FakeEvmRpc {
+    state: RefCell<State>,
+}
+
+#[derive(Default)]
+struct State {
+    head: u128,
+    finalized: Option<u128>,
+    block_hashes: BTreeMap<u128, String>,
+    logs: Vec<LogEntry>,
+    fee_history: Option<FeeHistory>,
+    transaction_counts: BTreeMap<String, u128>,
+    send_statuses: VecDeque<SendRawTransactionStatus>,
+    sent_transactions: Vec<String>,
+    receipts: BTreeMap<String, TransactionReceipt>,
+    responses: BTreeMap<String, serde_json::Value>,
+    requests: Vec<serde_json::Value>,
+    errors: BTreeMap<String, VecDeque<RpcError>>,
+}
+
+impl FakeEvmRpc {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the latest block. The safe and finalized blocks follow it unless set explicitly.
+    pub fn set_head(&self, number: u128) {
+        self.state.borrow_mut().head = number;
+    }
+
+    pub fn set_finalized(&self, number: u128) {
+        self.state.borrow_mut().finalized = Some(number);
+    }
+
+    /// Overrides the hash of a block, e.g. to simulate a reorg. Blocks get a hash derived from
+    /// their number by default.
+    pub fn set_block_hash(&self, number: u128, hash: &str) {
+        self.state
+            .borrow_mut()
+            .block_hashes
+            .insert(number, hash.to_string());
+    }
+
+    pub fn add_log(&self, log: LogEntry) {
+        self.state.borrow_mut().logs.push(log);
+    }
+
+    /// Removes the logs of a block and all later blocks, e.g. because they have been replaced by
+    /// a reorg.
+    pub fn remove_logs_from(&self, number: u128) {
+        self.state
+            .borrow_mut()
+            .logs
+            .retain(|log| log.blockNumber.is_some_and(|block| block < number));
+    }
+
+    pub fn set_fee_history(&self, fee_history: FeeHistory) {
+        self.state.borrow_mut().fee_history = Some(fee_history);
+    }
+
+    pub fn set_transaction_count(&self, address: &str, count: u128) {
+        self.state
+            .borrow_mut()
+            .transaction_counts
+            .insert(address.to_lowercase(), count);
+    }
+
+    /// Queues the status returned by the next `eth_sendRawTransaction`. Transactions are
+    /// accepted with `Ok` once the queue is empty.
+    pub fn push_send_status(&self, status: SendRawTransactionStatus) {
+        self.state.borrow_mut().send_statuses.push_back(status);
+    }
+
+    /// The raw transactions passed to `eth_sendRawTransaction`, in order.
+    pub fn sent_transactions(&self) -> Vec<String> {
+        self.state.borrow().sent_transactions.clone()
+    }
+
+    pub fn set_receipt(&self, receipt: TransactionReceipt) {
+        self.state
+            .borrow_mut()
+            .receipts
+            .insert(receipt.transactionHash.clone(), receipt);
+    }
+
+    /// Sets the result of a JSON-RPC method sent with `request`, e.g. `eth_estimateGas` or
+    /// `eth_call`. Other methods fail with "Method not found".
+    pub fn set_response(&self, method: &str, result: serde_json::Value) {
+        self.state
+            .borrow_mut()
+            .responses
+            .insert(method.to_string(), result);
+    }
+
+    /// The parameters of the requests of a JSON-RPC method sent with `request`, in order.
+    pub fn requests(&self, method: &str) -> Vec<serde_json::Value> {
+        self.state
+            .borrow()
+            .requests
+            .iter()
+            .filter(|request| request["method"] == method)
+            .map(|request| request["params"].clone())
+            .collect()
+    }
+
+    /// Makes the next call of a JSON-RPC method fail with `error`. Errors of the same method are
+    /// returned in the order they were queued.
+    pub fn fail_next(&self, method: &str, error: RpcError) {
+        self.state
+            .borrow_mut()
+            .errors
+            .entry(method.to_string())
+            .or_default()
+            .push_back(error);
+    }
+
+    fn take_error(&self, method: &str) -> Option<RpcError> {
+        self.state
+            .borrow_mut()
+            .errors
+            .get_mut(method)
+            .and_then(VecDeque::pop_front)
+    }
+
+    fn block_number(&self, tag: &BlockTag) -> u128 {
+        let state = self.state.borrow();
+        match tag {
+            BlockTag::Number(number) => *number,
+            BlockTag::Latest | BlockTag::Pending => state.head,
+            BlockTag::Safe | BlockTag::Finalized => state.finalized.unwrap_or(state.head),
+            BlockTag::Earliest => 0,
+        }
+    }
+
+    fn block(&self, number: u128) -> Block {
+        let hash = |number: u128| {
+            self.state
+                .borrow()
+                .block_hashes
+                .get(&number)
+                .cloned()
+                .unwrap_or(format!("0x{:064x}", number))
+        };
+        Block {
+            miner: format!("0x{:040x}", 0),
+            totalDifficulty: 0,
+            receiptsRoot: hash(0),
+            stateRoot: hash(0),
+            hash: hash(number),
+            difficulty: 0,
+            size: 0,
+            uncles: vec![],
+            baseFeePerGas: 0,
+            extraData: "0x".to_string(),
+            transactionsRoot: None,
+            sha3Uncles: hash(0),
+            nonce: 0,
+            number,
+            timestamp: 0,
+            transactions: vec![],
+            gasLimit: 30_000_000,
+            logsBloom: "0x".to_string(),
+            parentHash: hash(number.saturating_sub(1)),
+            gasUsed: 0,
+            mixHash: hash(0),
+        }
+    }
+
+    fn matching_logs(&self, args: &GetLogsArgs) -> Vec<LogEntry> {
+        let from = self.block_number(args.fromBlock.as_ref().unwrap_or(&BlockTag::Latest));
+        let to = self.block_number(args.toBlock.as_ref().unwrap_or(&BlockTag::Latest));
+        let topics = args.topics.clone().unwrap_or_default();
+        self.state
+            .borrow()
+            .logs
+            .iter()
+            .filter(|log| {
+                let block = log.blockNumber.unwrap_or_default();
+                (from..=to).contains(&block)
+                    && (args.addresses.is_empty()
+                        || args
+                            .addresses
+                            .iter()
+                            .any(|address| address.eq_ignore_ascii_case(&log.address)))
+                    && topics.iter().enumerate().all(|(i, allowed)| {
+                        allowed.is_empty()
+                            || log.topics.get(i).is_some_and(|topic| {
+                                allowed.iter().any(|t| t.eq_ignore_ascii_case(topic))
+                            })
+                    })
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn respond(&self, json: &str) -> Result<String, RpcError> {
+        let request: serde_json::Value = serde_json::from_str(json).unwrap_or_default();
+        self.state.borrow_mut().requests.push(request.clone());
+        let method = request["method"].as_str().unwrap_or_default();
+        if let Some(error) = self.take_error(method) {
+            return Err(error);
+        }
+        let response = match self.state.borrow().responses.get(method) {
+            Some(result) => serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": request["id"],
+                "result": result,
+            }),
+            None => serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": request["id"],
+                "error": { "code": -32601, "message": "Method not found" },
+            }),
+        };
+        Ok(response.to_string())
+    }
+}
+
+impl EvmRpcBackend for FakeEvmRpc {
+    fn get_logs(
+        &self,
+        _services: RpcServices,
+        _config: Option<RpcConfig>,
+        args: GetLogsArgs,
+        _cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiGetLogsResult,)>> {
+        let result = match self.take_error("eth_getLogs") {
+            Some(error) => GetLogsResult::Err(error),
+            None => GetLogsResult::Ok(self.matching_logs(&args)),
+        };
+        Box::pin(async move { Ok((MultiGetLogsResult::Consistent(result),)) })
+    }
+
+    fn eth_fee_history(
+        &self,
+        _services: RpcServices,
+        _config: Option<RpcConfig>,
+        _args: FeeHistoryArgs,
+        _cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiFeeHistoryResult,)>> {
+        let result = match self.take_error("eth_feeHistory") {
+            Some(error) => FeeHistoryResult::Err(error),
+            None => FeeHistoryResult::Ok(self.state.borrow().fee_history.clone()),
+        };
+        Box::pin(async move { Ok((MultiFeeHistoryResult::Consistent(result),)) })
+    }
+
+    fn eth_get_block_by_number(
+        &self,
+        _services: RpcServices,
+        _config: Option<RpcConfig>,
+        block: BlockTag,
+        _cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiGetBlockByNumberResult,)>> {
+        let result = match self.take_error("eth_getBlockByNumber") {
+            Some(error) => GetBlockByNumberResult::Err(error),
+            None => GetBlockByNumberResult::Ok(self.block(self.block_number(&block))),
+        };
+        Box::pin(async move { Ok((MultiGetBlockByNumberResult::Consistent(result),)) })
+    }
+
+    fn eth_get_transaction_count(
+        &self,
+        _services: RpcServices,
+        _config: Option<RpcConfig>,
+        args: GetTransactionCountArgs,
+        _cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiGetTransactionCountResult,)>> {
+        let result = match self.take_error("eth_getTransactionCount") {
+            Some(error) => GetTransactionCountResult::Err(error),
+            None => GetTransactionCountResult::Ok(
+                self.state
+                    .borrow()
+                    .transaction_counts
+                    .get(&args.address.to_lowercase())
+                    .copied()
+                    .unwrap_or_default(),
+            ),
+        };
+        Box::pin(async move { Ok((MultiGetTransactionCountResult::Consistent(result),)) })
+    }
+
+    fn eth_get_transaction_receipt(
+        &self,
+        _services: RpcServices,
+        _config: Option<RpcConfig>,
+        tx_hash: String,
+        _cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiGetTransactionReceiptResult,)>> {
+        let result = match self.take_error("eth_getTransactionReceipt") {
+            Some(error) => GetTransactionReceiptResult::Err(error),
+            None => {
+                GetTransactionReceiptResult::Ok(self.state.borrow().receipts.get(&tx_hash).cloned())
+            }
+        };
+        Box::pin(async move { Ok((MultiGetTransactionReceiptResult::Consistent(result),)) })
+    }
+
+    fn request(
+        &self,
+        _service: RpcService,
+        json: String,
+        _max_response_bytes: u64,
+        _cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(RequestResult,)>> {
+        let result = match self.respond(&json) {
+            Ok(response) => RequestResult::Ok(response),
+            Err(error) => RequestResult::Err(error),
+        };
+        Box::pin(async move { Ok((result,)) })
+    }
+
+    fn request_cost(
+        &self,
+        _service: RpcService,
+        _json: String,
+        _max_response_bytes: u64,
+    ) -> BoxFuture<'_, CallResult<(RequestCostResult,)>> {
+        Box::pin(async move { Ok((RequestCostResult::Ok(REQUEST_COST),)) })
+    }
+
+    fn eth_send_raw_transaction(
+        &self,
+        _services: RpcServices,
+        _config: Option<RpcConfig>,
+        raw_tx: String,
+        _cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiSendRawTransactionResult,)>> {
+        let result = match self.take_error("eth_sendRawTransaction") {
+            Some(error) => SendRawTransactionResult::Err(error),
+            None => {
+                let mut state = self.state.borrow_mut();
+                state.sent_transactions.push(raw_tx);
+                let status = state
+                    .send_statuses
+                    .pop_front()
+                    .unwrap_or(SendRawTransactionStatus::Ok);
+                SendRawTransactionResult::Ok(status)
+            }
+        };
+        Box::pin(async move { Ok((MultiSendRawTransactionResult::Consistent(result),)) })
+    }
+}
@@ -0,0 +1,163 @@
+use crate::error::CoprocessorError;
+use crate::evm_rpc::{self, BlockTag, FeeHistory};
+use crate::memory::{Chain, FeeSettings};
+
+use serde_bytes::ByteBuf;
+
+/// Number of recent blocks whose priority fees are taken into account.
+const FEE_HISTORY_BLOCKS: u128 = 10;
+
+/// The EIP-1559 fees of a transaction, in wei per gas.
+pub struct Fees {
+    pub max_fee_per_gas: u128,
+    pub max_priority_fee_per_gas: u128,
+}
+
+/// Estimates the fees for a transaction to be included in one of the next blocks of a chain,
+/// according to its `FeeSettings`.
+///
+/// The tip is the median, over the recent blocks, of the configured reward percentile. The
+/// maximum fee allows for the projected base fee of the next block times the configured
+/// multiplier, plus the tip.
+pub async fn estimate(chain: &Chain) -> Result<Fees, CoprocessorError> {
+    let settings = chain.fees();
+
+    let percentiles = ByteBuf::from(vec![settings.reward_percentile.min(100)]);
+    let history = evm_rpc::fee_history(
+        chain,
+        FEE_HISTORY_BLOCKS,
+        BlockTag::Latest,
+        Some(percentiles),
+    )
+    .await?;
+    from_history(&settings, &history)
+}
+
+/// Computes the fees of `estimate` from the fee history of the recent blocks.
+fn from_history(settings: &FeeSettings, history: &FeeHistory) -> Result<Fees, CoprocessorError> {
+    // The base fees include the one of the block following the newest block
+    let next_base_fee = *history
+        .baseFeePerGas
+        .last()
+        .ok_or(CoprocessorError::InvalidResponse(
+            "Fee history contains no base fees".to_string(),
+        ))?;
+
+    // Empty blocks report a reward of zero, which would drag the tip down
+    let mut rewards: Vec<u128> = history
+        .reward
+        .iter()
+        .filter_map(|block_rewards| block_rewards.first().copied())
+        .filter(|reward| *reward > 0)
+        .collect();
+    rewards.sort_unstable();
+    let reward = rewards.get(rewards.len() / 2).copied().unwrap_or(0);
+
+    let mut max_priority_fee_per_gas = reward.max(settings.min_priority_fee_per_gas);
+    if let Some(cap) = settings.max_priority_fee_per_gas {
+        max_priority_fee_per_gas = max_priority_fee_per_gas.min(cap);
+    }
+
+    let max_fee_per_gas =
+        next_base_fee.saturating_mul(settings.base_fee_multiplier_percent.max(100) as u128) / 100;
+    let max_fee_per_gas = max_fee_per_gas.saturating_add(max_priority_fee_per_gas);
+
+    let max_fee_per_gas = match settings.max_fee_per_gas {
+        Some(cap) if next_base_fee.saturating_add(max_priority_fee_per_gas) > cap => {
+            return Err(CoprocessorError::FeeCapExceeded {
+                required: next_base_fee.saturating_add(max_priority_fee_per_gas),
+                cap,
+            });
+        }
+        Some(cap) => max_fee_per_gas.min(cap),
+        None => max_fee_per_gas,
+    };
+
+    Ok(Fees {
+        max_fee_per_gas,
+        max_priority_fee_per_gas,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(rewards: &[u128], next_base_fee: u128) -> FeeHistory {
+        FeeHistory {
+            reward: rewards.iter().map(|reward| vec![*reward]).collect(),
+            gasUsedRatio: vec![0.5; rewards.len()],
+            oldestBlock: 100,
+            baseFeePerGas: vec![next_base_fee; rewards.len() + 1],
+        }
+    }
+
+    fn settings() -> FeeSettings {
+        FeeSettings {
+            reward_percentile: 50,
+            base_fee_multiplier_percent: 200,
+            min_priority_fee_per_gas: 1_000,
+            max_priority_fee_per_gas: None,
+            max_fee_per_gas: None,
+            replace_after_secs: 180,
+        }
+    }
+
+    #[test]
+    fn tip_is_the_median_of_the_non_zero_rewards() {
+        let fees = from_history(
+            &settings(),
+            &history(&[0, 30_000, 0, 10_000, 20_000], 100_000),
+        )
+        .unwrap();
+        assert_eq!(fees.max_priority_fee_per_gas, 20_000);
+        assert_eq!(fees.max_fee_per_gas, 200_000 + 20_000);
+
+        // Only empty blocks: the minimum tip is offered
+        let fees = from_history(&settings(), &history(&[0, 0, 0], 100_000)).unwrap();
+        assert_eq!(fees.max_priority_fee_per_gas, 1_000);
+        assert_eq!(fees.max_fee_per_gas, 200_000 + 1_000);
+    }
+
+    #[test]
+    fn base_fee_multiplier_is_at_least_100_percent() {
+        let settings = FeeSettings {
+            base_fee_multiplier_percent: 50,
+            ..settings()
+        };
+        let fees = from_history(&settings, &history(&[5_000], 100_000)).unwrap();
+        assert_eq!(fees.max_fee_per_gas, 100_000 + 5_000);
+    }
+
+    #[test]
+    fn fees_saturate_and_are_capped() {
+        let fees = from_history(&settings(), &history(&[u128::MAX], u128::MAX)).unwrap();
+        assert_eq!(fees.max_priority_fee_per_gas, u128::MAX);
+        assert_eq!(fees.max_fee_per_gas, u128::MAX);
+
+        let settings = FeeSettings {
+            max_priority_fee_per_gas: Some(2_000),
+            max_fee_per_gas: Some(150_000),
+            ..settings()
+        };
+        let fees = from_history(&settings, &history(&[5_000], 100_000)).unwrap();
+        assert_eq!(fees.max_priority_fee_per_gas, 2_000);
+        // The multiplied base fee is cut down to the cap, which still covers the next block
+        assert_eq!(fees.max_fee_per_gas, 150_000);
+    }
+
+    #[test]
+    fn fails_while_the_next_block_exceeds_the_fee_cap() {
+        let settings = FeeSettings {
+            max_fee_per_gas: Some(100_000),
+            ..settings()
+        };
+        match from_history(&settings, &history(&[5_000], 100_000)) {
+            Err(CoprocessorError::FeeCapExceeded { required, cap }) => {
+                assert_eq!((required, cap), (105_000, 100_000))
+            }
+            Err(error) => panic!("Unexpected error: {}", error),
+            Ok(_) => panic!("The fees exceed the cap"),
+        }
+    }
+}
@@ -0,0 +1,24 @@
+use std::cell::Cell;
+use std::thread::LocalKey;
+
+/// Prevents overlapping runs of a periodic task, e.g. when a run takes longer than the interval
+/// of its timer. The flag of the task is cleared when the guard is dropped.
+pub struct RunGuard {
+    flag: &'static LocalKey<Cell<bool>>,
+}
+
+impl RunGuard {
+    /// Returns `None` if another run of the task holds the flag.
+    pub fn new(flag: &'static LocalKey<Cell<bool>>) -> Option<Self> {
+        if flag.with(|flag| flag.replace(true)) {
+            return None;
+        }
+        Some(RunGuard { flag })
+    }
+}
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        self.flag.with(|flag| flag.set(false));
+    }
+}
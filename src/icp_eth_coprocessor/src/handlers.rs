@@ -0,0 +1,194 @@
+use crate::chains;
+use crate::events::{self, AbiValue, DecodedEvent};
+use crate::evm_rpc::{self, BlockTag};
+use crate::memory::{Subscription, STATE};
+use crate::runtime::{self, BoxFuture};
+
+use ethers_core::abi::EventExt;
+
+use std::{cell::RefCell, rc::Rc};
+
+/// ABI of the event emitted by the contract for every new job.
+pub const NEW_JOB_EVENT: &str = "event NewJob(uint256 job_id)";
+
+/// Information about the log that triggered a job. Handlers use what they need.
+#[derive(Clone, Debug)]
+pub struct JobContext {
+    pub subscription_id: u64,
+    pub chain_id: u64,
+    pub contract: String,
+    pub block_number: u128,
+    pub transaction_hash: String,
+    pub log_index: u128,
+    /// The sender of the transaction that emitted the event.
+    pub requester: Option<String>,
+}
+
+impl JobContext {
+    /// Calls a view function of a contract, given by its human readable ABI, e.g.
+    /// `function balanceOf(address owner) view returns (uint256)`, and returns the decoded
+    /// return values. The contract state is read as of the block of the log that triggered the
+    /// job, so that the result does not depend on when the job is processed.
+    pub async fn call(
+        &self,
+        contract: &str,
+        function: &str,
+        args: &[AbiValue],
+    ) -> Result<Vec<AbiValue>, String> {
+        let function = events::parse_function(function)?;
+        let data = events::encode_call(&function, args)?;
+        let chain =
+            chains::get(self.chain_id).ok_or(format!("Chain {} not found", self.chain_id))?;
+        let output = evm_rpc::call(
+            &chain,
+            contract.to_string(),
+            &data,
+            BlockTag::Number(self.block_number),
+        )
+        .await
+        .map_err(|e| format!("Failed to call {} of {}: {}", function.name, contract, e))?;
+        events::decode_output(&function, &output)
+    }
+}
+
+/// The arguments of the callback that delivers the result to the contract.
+pub type JobResult = Vec<AbiValue>;
+
+/// Processes the jobs created by one kind of event.
+pub trait JobHandler {
+    fn handle<'a>(
+        &'a self,
+        context: &'a JobContext,
+        event: &'a DecodedEvent,
+    ) -> BoxFuture<'a, Result<JobResult, String>>;
+}
+
+struct Registration {
+    name: String,
+    contract: Option<String>,
+    signature: String,
+    handler: Rc<dyn JobHandler>,
+}
+
+thread_local! {
+    static HANDLERS: RefCell<Vec<Registration>> = RefCell::default();
+}
+
+/// Registers all job handlers of the canister. Called on init and after every upgrade.
+pub fn register_handlers() {
+    register("new_job", None, NEW_JOB_EVENT, NewJobHandler);
+}
+
+/// Registers a handler for an event, given by its human readable ABI, e.g.
+/// `event NewJob(uint256 job_id)`. If `contract` is `None`, the handler serves all contracts.
+/// Subscriptions can also refer to a handler explicitly by its `name`.
+pub fn register(
+    name: &str,
+    contract: Option<&str>,
+    event_abi: &str,
+    handler: impl JobHandler + 'static,
+) {
+    let event = events::parse_event(event_abi).unwrap_or_else(|e| ic_cdk::trap(&e));
+    HANDLERS.with(|handlers| {
+        handlers.borrow_mut().push(Registration {
+            name: name.to_string(),
+            contract: contract.map(normalize),
+            signature: event.abi_signature(),
+            handler: Rc::new(handler),
+        })
+    });
+}
+
+/// Looks up the handler for an event of a subscription. A handler named by the subscription
+/// takes precedence, followed by handlers registered for the contract and handlers serving
+/// all contracts.
+pub fn handler_for(subscription: &Subscription, signature: &str) -> Option<Rc<dyn JobHandler>> {
+    HANDLERS.with(|handlers| {
+        let handlers = handlers.borrow();
+        let found = match &subscription.handler {
+            Some(name) => handlers.iter().find(|r| &r.name == name),
+            None => {
+                let contract = Some(normalize(&subscription.contract));
+                handlers
+                    .iter()
+                    .filter(|r| r.signature == signature)
+                    .find(|r| r.contract == contract)
+                    .or_else(|| {
+                        handlers
+                            .iter()
+                            .filter(|r| r.signature == signature)
+                            .find(|r| r.contract.is_none())
+                    })
+            }
+        };
+        found.map(|registration| registration.handler.clone())
+    })
+}
+
+fn normalize(address: &str) -> String {
+    address.to_lowercase()
+}
+
+/// Handles the `NewJob` event of `contracts/coprocessor.sol`.
+struct NewJobHandler;
+
+impl NewJobHandler {
+    /// The contract only accepts callbacks from the address stored as its `coprocessor`. Jobs of
+    /// a contract that is served by another address fail instead of paying for a callback that
+    /// reverts. If the address cannot be read, the callback is sent anyway.
+    async fn check_coprocessor(context: &JobContext) -> Result<(), String> {
+        let evm_address = match STATE.with(|state| state.borrow().get().evm_address.clone()) {
+            Some(evm_address) => evm_address,
+            None => return Ok(()),
+        };
+        let coprocessor = context
+            .call(
+                &context.contract,
+                "function coprocessor() view returns (address)",
+                &[],
+            )
+            .await;
+        match coprocessor.as_deref() {
+            Ok([AbiValue::Address(coprocessor)])
+                if !coprocessor.eq_ignore_ascii_case(&evm_address) =>
+            {
+                Err(format!(
+                    "Contract {} only accepts callbacks from {}",
+                    context.contract, coprocessor
+                ))
+            }
+            Ok(_) => Ok(()),
+            Err(error) => {
+                runtime::print(format!(
+                    "Could not read the coprocessor of {}: {}",
+                    context.contract, error
+                ));
+                Ok(())
+            }
+        }
+    }
+}
+
+impl JobHandler for NewJobHandler {
+    fn handle<'a>(
+        &'a self,
+        context: &'a JobContext,
+        event: &'a DecodedEvent,
+    ) -> BoxFuture<'a, Result<JobResult, String>> {
+        Box::pin(async move {
+            let job_id = event.uint("job_id")?;
+            runtime::print(format!(
+                "Processing job {} of subscription {}, requested by {} in log {} of transaction {}",
+                job_id,
+                context.subscription_id,
+                context.requester.as_deref().unwrap_or("an unknown sender"),
+                context.log_index,
+                context.transaction_hash
+            ));
+            Self::check_coprocessor(context).await?;
+            //TODO: Implement the actual job processing logic
+            let result = "42";
+            Ok(vec![AbiValue::String(result.to_string())])
+        })
+    }
+}
@@ -0,0 +1,675 @@
+use ic_cdk::api::call::{CallResult, RejectionCode as IcRejectionCode};
+use ic_cdk::api::management_canister::http_request::{
+    self, CanisterHttpRequestArgument, HttpMethod, HttpResponse, TransformContext,
+};
+
+use serde_json::Value;
+
+use crate::evm_rpc::{
+    self, Block, BlockTag, FeeHistory, FeeHistoryArgs, FeeHistoryResult, GetBlockByNumberResult,
+    GetLogsArgs, GetLogsResult, GetTransactionCountArgs, GetTransactionCountResult,
+    GetTransactionReceiptResult, HttpOutcallError, JsonRpcError, LogEntry, MultiFeeHistoryResult,
+    MultiGetBlockByNumberResult, MultiGetLogsResult, MultiGetTransactionCountResult,
+    MultiGetTransactionReceiptResult, MultiSendRawTransactionResult, ProviderError, RejectionCode,
+    RequestCostResult, RequestResult, RpcApi, RpcConfig, RpcError, RpcService, RpcServices,
+    SendRawTransactionResult, SendRawTransactionStatus, TransactionReceipt,
+};
+use crate::memory::CONFIG;
+use crate::rpc_backend::EvmRpcBackend;
+use crate::runtime::BoxFuture;
+
+/// The query that removes the non-deterministic parts of the responses, see `transform`.
+pub const TRANSFORM_METHOD: &str = "transform_rpc_response";
+
+/// The number of nodes of an application subnet, used for the cost of HTTPS outcalls unless
+/// another subnet size is configured with `InitArgs`.
+const DEFAULT_SUBNET_SIZE: u32 = 13;
+
+/// Used if the call does not come with a response size estimate.
+const MAX_RESPONSE_BYTES: u64 = 2_000_000;
+
+/// The results of the providers of a replicated call, or the error that prevented the call.
+type Responses<T> = Result<Vec<(RpcService, Result<T, RpcError>)>, RpcError>;
+
+/// Makes the JSON-RPC calls to the providers of a chain directly with HTTPS outcalls instead of
+/// through the EVM RPC canister. Only custom providers are supported, as the URLs and API keys
+/// of the named providers are only known to the EVM RPC canister.
+///
+/// Like the EVM RPC canister, a replicated call is sent to all providers, and the result is
+/// `Consistent` if they return the same response.
+pub struct HttpOutcalls;
+
+impl EvmRpcBackend for HttpOutcalls {
+    fn get_logs(
+        &self,
+        services: RpcServices,
+        config: Option<RpcConfig>,
+        args: GetLogsArgs,
+        cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiGetLogsResult,)>> {
+        Box::pin(async move {
+            // An empty list of topics matches any topic at its position
+            let topics = args.topics.map(|topics| {
+                topics
+                    .into_iter()
+                    .map(|topic| (!topic.is_empty()).then_some(topic))
+                    .collect::<Vec<_>>()
+            });
+            let filter = serde_json::json!({
+                "fromBlock": args.fromBlock.as_ref().map(evm_rpc::block_tag_param),
+                "toBlock": args.toBlock.as_ref().map(evm_rpc::block_tag_param),
+                "address": args.addresses,
+                "topics": topics,
+            });
+            let results = replicated(&services, config, cycles, "eth_getLogs", [filter], |logs| {
+                array(&logs)?.iter().map(log_entry).collect()
+            })
+            .await;
+            Ok((combine(
+                results,
+                GetLogsResult::Ok,
+                GetLogsResult::Err,
+                MultiGetLogsResult::Consistent,
+                MultiGetLogsResult::Inconsistent,
+            ),))
+        })
+    }
+
+    fn eth_fee_history(
+        &self,
+        services: RpcServices,
+        config: Option<RpcConfig>,
+        args: FeeHistoryArgs,
+        cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiFeeHistoryResult,)>> {
+        Box::pin(async move {
+            let percentiles = args
+                .rewardPercentiles
+                .map(|percentiles| percentiles.to_vec());
+            let params = serde_json::json!([
+                format!("{:#x}", args.blockCount),
+                evm_rpc::block_tag_param(&args.newestBlock),
+                percentiles.unwrap_or_default(),
+            ]);
+            let results = replicated(
+                &services,
+                config,
+                cycles,
+                "eth_feeHistory",
+                params,
+                |fees| optional(&fees, fee_history),
+            )
+            .await;
+            Ok((combine(
+                results,
+                FeeHistoryResult::Ok,
+                FeeHistoryResult::Err,
+                MultiFeeHistoryResult::Consistent,
+                MultiFeeHistoryResult::Inconsistent,
+            ),))
+        })
+    }
+
+    fn eth_get_block_by_number(
+        &self,
+        services: RpcServices,
+        config: Option<RpcConfig>,
+        block: BlockTag,
+        cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiGetBlockByNumberResult,)>> {
+        Box::pin(async move {
+            // Only the hashes of the transactions are requested
+            let params = serde_json::json!([evm_rpc::block_tag_param(&block), false]);
+            let method = "eth_getBlockByNumber";
+            let results = replicated(&services, config, cycles, method, params, |block| {
+                optional(&block, self::block)?.ok_or_else(|| invalid(&block, "block not found"))
+            })
+            .await;
+            Ok((combine(
+                results,
+                GetBlockByNumberResult::Ok,
+                GetBlockByNumberResult::Err,
+                MultiGetBlockByNumberResult::Consistent,
+                MultiGetBlockByNumberResult::Inconsistent,
+            ),))
+        })
+    }
+
+    fn eth_get_transaction_count(
+        &self,
+        services: RpcServices,
+        config: Option<RpcConfig>,
+        args: GetTransactionCountArgs,
+        cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiGetTransactionCountResult,)>> {
+        Box::pin(async move {
+            let params = serde_json::json!([args.address, evm_rpc::block_tag_param(&args.block)]);
+            let method = "eth_getTransactionCount";
+            let results = replicated(&services, config, cycles, method, params, |count| {
+                quantity(&count)
+            })
+            .await;
+            Ok((combine(
+                results,
+                GetTransactionCountResult::Ok,
+                GetTransactionCountResult::Err,
+                MultiGetTransactionCountResult::Consistent,
+                MultiGetTransactionCountResult::Inconsistent,
+            ),))
+        })
+    }
+
+    fn eth_get_transaction_receipt(
+        &self,
+        services: RpcServices,
+        config: Option<RpcConfig>,
+        tx_hash: String,
+        cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiGetTransactionReceiptResult,)>> {
+        Box::pin(async move {
+            let method = "eth_getTransactionReceipt";
+            let results = replicated(&services, config, cycles, method, [tx_hash], |receipt| {
+                optional(&receipt, transaction_receipt)
+            })
+            .await;
+            Ok((combine(
+                results,
+                GetTransactionReceiptResult::Ok,
+                GetTransactionReceiptResult::Err,
+                MultiGetTransactionReceiptResult::Consistent,
+                MultiGetTransactionReceiptResult::Inconsistent,
+            ),))
+        })
+    }
+
+    fn request(
+        &self,
+        service: RpcService,
+        json: String,
+        max_response_bytes: u64,
+        cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(RequestResult,)>> {
+        Box::pin(async move {
+            let result = match custom_api(service) {
+                Ok(api) => post(&api, json, max_response_bytes, cycles).await,
+                Err(error) => Err(error),
+            };
+            Ok((match result {
+                Ok(response) => RequestResult::Ok(response),
+                Err(error) => RequestResult::Err(error),
+            },))
+        })
+    }
+
+    fn request_cost(
+        &self,
+        service: RpcService,
+        json: String,
+        max_response_bytes: u64,
+    ) -> BoxFuture<'_, CallResult<(RequestCostResult,)>> {
+        Box::pin(async move {
+            Ok((match custom_api(service) {
+                Ok(api) => RequestCostResult::Ok(outcall_cost(
+                    &api,
+                    &json,
+                    max_response_bytes,
+                    subnet_size(),
+                )),
+                Err(error) => RequestCostResult::Err(error),
+            },))
+        })
+    }
+
+    fn eth_send_raw_transaction(
+        &self,
+        services: RpcServices,
+        config: Option<RpcConfig>,
+        raw_tx: String,
+        cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiSendRawTransactionResult,)>> {
+        Box::pin(async move {
+            let method = "eth_sendRawTransaction";
+            let results = replicated(&services, config, cycles, method, [raw_tx], |_| {
+                Ok(SendRawTransactionStatus::Ok)
+            })
+            .await
+            .map(|results| {
+                results
+                    .into_iter()
+                    .map(|(service, result)| (service, result.or_else(send_status)))
+                    .collect()
+            });
+            Ok((combine(
+                results,
+                SendRawTransactionResult::Ok,
+                SendRawTransactionResult::Err,
+                MultiSendRawTransactionResult::Consistent,
+                MultiSendRawTransactionResult::Inconsistent,
+            ),))
+        })
+    }
+}
+
+/// Removes the headers of a response, which contain dates and request IDs, and re-encodes a
+/// JSON body so that the order of its fields and its whitespace do not matter. The `data` of
+/// JSON-RPC errors, which may contain provider-specific details, is dropped. Bodies that are
+/// not JSON, e.g. error pages, are dropped as well.
+pub fn transform(response: HttpResponse) -> HttpResponse {
+    let body = match serde_json::from_slice::<Value>(&response.body) {
+        Ok(mut body) => {
+            if let Some(error) = body.get_mut("error").and_then(Value::as_object_mut) {
+                error.remove("data");
+            }
+            body.to_string().into_bytes()
+        }
+        Err(_) => Vec::new(),
+    };
+    HttpResponse {
+        status: response.status,
+        headers: Vec::new(),
+        body,
+    }
+}
+
+/// Sends a JSON-RPC request to all providers and parses the results with `parse`. The cycles
+/// are split evenly between the providers. Fails if the providers are not custom providers.
+async fn replicated<T>(
+    services: &RpcServices,
+    config: Option<RpcConfig>,
+    cycles: u128,
+    method: &str,
+    params: impl serde::Serialize,
+    parse: impl Fn(Value) -> Result<T, RpcError>,
+) -> Responses<T> {
+    let apis = match services {
+        RpcServices::Custom { services, .. } => services.clone(),
+        RpcServices::EthMainnet(_) | RpcServices::EthSepolia(_) => return Err(provider_not_found()),
+    };
+    let max_response_bytes = config
+        .and_then(|config| config.responseSizeEstimate)
+        .unwrap_or(MAX_RESPONSE_BYTES);
+    let cycles = cycles / apis.len().max(1) as u128;
+    let request = serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": method,
+        "params": params,
+    })
+    .to_string();
+
+    let mut results = Vec::new();
+    for api in apis {
+        let result = post(&api, request.clone(), max_response_bytes, cycles)
+            .await
+            .and_then(|response| json_rpc_result(&response))
+            .and_then(&parse);
+        results.push((RpcService::Custom(api), result));
+    }
+    Ok(results)
+}
+
+/// Makes an HTTPS outcall with a JSON-RPC request and returns the transformed response body.
+async fn post(
+    api: &RpcApi,
+    json: String,
+    max_response_bytes: u64,
+    cycles: u128,
+) -> Result<String, RpcError> {
+    let mut headers = vec![http_request::HttpHeader {
+        name: "Content-Type".to_string(),
+        value: "application/json".to_string(),
+    }];
+    headers.extend(
+        api.headers
+            .iter()
+            .flatten()
+            .map(|header| http_request::HttpHeader {
+                name: header.name.clone(),
+                value: header.value.clone(),
+            }),
+    );
+    let request = CanisterHttpRequestArgument {
+        url: api.url.clone(),
+        max_response_bytes: Some(max_response_bytes),
+        method: HttpMethod::POST,
+        headers,
+        body: Some(json.into_bytes()),
+        transform: Some(TransformContext::from_name(
+            TRANSFORM_METHOD.to_string(),
+            Vec::new(),
+        )),
+    };
+
+    let (response,) =
+        http_request::http_request(request, cycles)
+            .await
+            .map_err(|(code, message)| {
+                RpcError::HttpOutcallError(HttpOutcallError::IcError {
+                    code: rejection_code(code),
+                    message,
+                })
+            })?;
+    let body = String::from_utf8_lossy(&response.body).to_string();
+    let status = u16::try_from(response.status.0).unwrap_or(u16::MAX);
+    if !(200..300).contains(&status) {
+        return Err(RpcError::HttpOutcallError(
+            HttpOutcallError::InvalidHttpJsonRpcResponse {
+                status,
+                body,
+                parsingError: None,
+            },
+        ));
+    }
+    Ok(body)
+}
+
+/// The result of a JSON-RPC response, or its error.
+fn json_rpc_result(response: &str) -> Result<Value, RpcError> {
+    let mut envelope: Value = serde_json::from_str(response).map_err(|e| {
+        RpcError::HttpOutcallError(HttpOutcallError::InvalidHttpJsonRpcResponse {
+            status: 200,
+            body: response.to_string(),
+            parsingError: Some(e.to_string()),
+        })
+    })?;
+    if let Some(error) = envelope.get("error") {
+        return Err(RpcError::JsonRpcError(JsonRpcError {
+            code: error["code"].as_i64().unwrap_or_default(),
+            message: error["message"].as_str().unwrap_or_default().to_string(),
+        }));
+    }
+    Ok(envelope["result"].take())
+}
+
+/// Interprets the errors of `eth_sendRawTransaction` like the EVM RPC canister. A transaction
+/// the provider already knows has been accepted before.
+fn send_status(error: RpcError) -> Result<SendRawTransactionStatus, RpcError> {
+    let message = match &error {
+        RpcError::JsonRpcError(JsonRpcError { message, .. }) => message.to_lowercase(),
+        _ => return Err(error),
+    };
+    if message.contains("already known") {
+        Ok(SendRawTransactionStatus::Ok)
+    } else if message.contains("nonce too low") {
+        Ok(SendRawTransactionStatus::NonceTooLow)
+    } else if message.contains("nonce too high") {
+        Ok(SendRawTransactionStatus::NonceTooHigh)
+    } else if message.contains("insufficient funds") {
+        Ok(SendRawTransactionStatus::InsufficientFunds)
+    } else {
+        Err(error)
+    }
+}
+
+/// Builds the result of a replicated call. It is `Consistent` if there is a single provider or
+/// all providers returned the same successful result.
+fn combine<T: PartialEq, R, M>(
+    results: Responses<T>,
+    ok: fn(T) -> R,
+    err: fn(RpcError) -> R,
+    consistent: fn(R) -> M,
+    inconsistent: fn(Vec<(RpcService, R)>) -> M,
+) -> M {
+    let mut results = match results {
+        Ok(results) => results,
+        Err(error) => return consistent(err(error)),
+    };
+    let into_result = |result: Result<T, RpcError>| match result {
+        Ok(value) => ok(value),
+        Err(error) => err(error),
+    };
+    let agree = match results.first() {
+        Some((_, Ok(first))) => results
+            .iter()
+            .all(|(_, result)| result.as_ref().is_ok_and(|value| value == first)),
+        _ => results.len() == 1,
+    };
+    if agree {
+        let (_, result) = results.swap_remove(0);
+        return consistent(into_result(result));
+    }
+    inconsistent(
+        results
+            .into_iter()
+            .map(|(service, result)| (service, into_result(result)))
+            .collect(),
+    )
+}
+
+fn subnet_size() -> u32 {
+    CONFIG
+        .with(|config| config.borrow().get().subnet_size)
+        .unwrap_or(DEFAULT_SUBNET_SIZE)
+}
+
+/// The cycles an HTTPS outcall to `api` costs on a subnet of `subnet_size` nodes, see
+/// https://internetcomputer.org/docs/current/developer-docs/gas-cost
+fn outcall_cost(api: &RpcApi, json: &str, max_response_bytes: u64, subnet_size: u32) -> u128 {
+    let subnet_size = subnet_size as u128;
+    let request_bytes = api.url.len()
+        + json.len()
+        + api
+            .headers
+            .iter()
+            .flatten()
+            .map(|header| header.name.len() + header.value.len())
+            .sum::<usize>();
+    (3_000_000 + 60_000 * subnet_size) * subnet_size
+        + 400 * subnet_size * request_bytes as u128
+        + 800 * subnet_size * max_response_bytes as u128
+}
+
+fn custom_api(service: RpcService) -> Result<RpcApi, RpcError> {
+    match service {
+        RpcService::Custom(api) => Ok(api),
+        _ => Err(provider_not_found()),
+    }
+}
+
+fn provider_not_found() -> RpcError {
+    RpcError::ProviderError(ProviderError::ProviderNotFound)
+}
+
+fn rejection_code(code: IcRejectionCode) -> RejectionCode {
+    match code {
+        IcRejectionCode::NoError => RejectionCode::NoError,
+        IcRejectionCode::SysFatal => RejectionCode::SysFatal,
+        IcRejectionCode::SysTransient => RejectionCode::SysTransient,
+        IcRejectionCode::DestinationInvalid => RejectionCode::DestinationInvalid,
+        IcRejectionCode::CanisterReject => RejectionCode::CanisterReject,
+        IcRejectionCode::CanisterError => RejectionCode::CanisterError,
+        IcRejectionCode::Unknown => RejectionCode::Unknown,
+    }
+}
+
+fn invalid(value: &Value, error: &str) -> RpcError {
+    RpcError::HttpOutcallError(HttpOutcallError::InvalidHttpJsonRpcResponse {
+        status: 200,
+        body: value.to_string(),
+        parsingError: Some(error.to_string()),
+    })
+}
+
+/// Parses a value that is null if it does not exist, e.g. the receipt of a pending transaction.
+fn optional<T>(
+    value: &Value,
+    parse: impl Fn(&Value) -> Result<T, RpcError>,
+) -> Result<Option<T>, RpcError> {
+    match value {
+        Value::Null => Ok(None),
+        value => parse(value).map(Some),
+    }
+}
+
+fn array(value: &Value) -> Result<&Vec<Value>, RpcError> {
+    value
+        .as_array()
+        .ok_or_else(|| invalid(value, "expected an array"))
+}
+
+fn string(value: &Value) -> Result<String, RpcError> {
+    value
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| invalid(value, "expected a string"))
+}
+
+fn strings(value: &Value) -> Result<Vec<String>, RpcError> {
+    array(value)?.iter().map(string).collect()
+}
+
+fn quantity(value: &Value) -> Result<u128, RpcError> {
+    value
+        .as_str()
+        .and_then(|quantity| u128::from_str_radix(quantity.trim_start_matches("0x"), 16).ok())
+        .ok_or_else(|| invalid(value, "expected a hexadecimal quantity"))
+}
+
+fn quantities(value: &Value) -> Result<Vec<u128>, RpcError> {
+    array(value)?.iter().map(quantity).collect()
+}
+
+/// A quantity that is missing on some chains, e.g. `totalDifficulty` after the merge.
+fn quantity_or_zero(value: &Value) -> Result<u128, RpcError> {
+    optional(value, quantity).map(Option::unwrap_or_default)
+}
+
+fn log_entry(log: &Value) -> Result<LogEntry, RpcError> {
+    Ok(LogEntry {
+        transactionHash: optional(&log["transactionHash"], string)?,
+        blockNumber: optional(&log["blockNumber"], quantity)?,
+        data: string(&log["data"])?,
+        blockHash: optional(&log["blockHash"], string)?,
+        transactionIndex: optional(&log["transactionIndex"], quantity)?,
+        topics: strings(&log["topics"])?,
+        address: string(&log["address"])?,
+        logIndex: optional(&log["logIndex"], quantity)?,
+        removed: log["removed"].as_bool().unwrap_or(false),
+    })
+}
+
+fn fee_history(fees: &Value) -> Result<FeeHistory, RpcError> {
+    Ok(FeeHistory {
+        reward: optional(&fees["reward"], |rewards| {
+            array(rewards)?.iter().map(quantities).collect()
+        })?
+        .unwrap_or_default(),
+        gasUsedRatio: array(&fees["gasUsedRatio"])?
+            .iter()
+            .map(|ratio| {
+                ratio
+                    .as_f64()
+                    .ok_or_else(|| invalid(ratio, "expected a number"))
+            })
+            .collect::<Result<_, _>>()?,
+        oldestBlock: quantity(&fees["oldestBlock"])?,
+        baseFeePerGas: quantities(&fees["baseFeePerGas"])?,
+    })
+}
+
+fn block(block: &Value) -> Result<Block, RpcError> {
+    Ok(Block {
+        miner: string(&block["miner"])?,
+        totalDifficulty: quantity_or_zero(&block["totalDifficulty"])?,
+        receiptsRoot: string(&block["receiptsRoot"])?,
+        stateRoot: string(&block["stateRoot"])?,
+        hash: string(&block["hash"])?,
+        difficulty: quantity_or_zero(&block["difficulty"])?,
+        size: quantity_or_zero(&block["size"])?,
+        uncles: optional(&block["uncles"], strings)?.unwrap_or_default(),
+        baseFeePerGas: quantity_or_zero(&block["baseFeePerGas"])?,
+        extraData: string(&block["extraData"])?,
+        transactionsRoot: optional(&block["transactionsRoot"], string)?,
+        sha3Uncles: string(&block["sha3Uncles"])?,
+        nonce: quantity_or_zero(&block["nonce"])?,
+        number: quantity(&block["number"])?,
+        timestamp: quantity(&block["timestamp"])?,
+        transactions: optional(&block["transactions"], strings)?.unwrap_or_default(),
+        gasLimit: quantity(&block["gasLimit"])?,
+        logsBloom: string(&block["logsBloom"])?,
+        parentHash: string(&block["parentHash"])?,
+        gasUsed: quantity(&block["gasUsed"])?,
+        mixHash: optional(&block["mixHash"], string)?.unwrap_or_default(),
+    })
+}
+
+fn transaction_receipt(receipt: &Value) -> Result<TransactionReceipt, RpcError> {
+    Ok(TransactionReceipt {
+        // Null for contract creations
+        to: optional(&receipt["to"], string)?.unwrap_or_default(),
+        status: quantity(&receipt["status"])?,
+        transactionHash: string(&receipt["transactionHash"])?,
+        blockNumber: quantity(&receipt["blockNumber"])?,
+        from: string(&receipt["from"])?,
+        logs: array(&receipt["logs"])?
+            .iter()
+            .map(log_entry)
+            .collect::<Result<_, _>>()?,
+        blockHash: string(&receipt["blockHash"])?,
+        r#type: optional(&receipt["type"], string)?.unwrap_or("0x0".to_string()),
+        transactionIndex: quantity(&receipt["transactionIndex"])?,
+        effectiveGasPrice: quantity(&receipt["effectiveGasPrice"])?,
+        logsBloom: string(&receipt["logsBloom"])?,
+        contractAddress: optional(&receipt["contractAddress"], string)?,
+        gasUsed: quantity(&receipt["gasUsed"])?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_cdk::api::management_canister::http_request::HttpHeader;
+
+    use super::*;
+
+    fn response(body: &str) -> HttpResponse {
+        HttpResponse {
+            status: 200u64.into(),
+            headers: vec![HttpHeader {
+                name: "date".to_string(),
+                value: "Mon, 01 Jan 2024 00:00:00 GMT".to_string(),
+            }],
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn transform_strips_headers_and_re_encodes_json() {
+        let transformed = transform(response("{ \"result\": \"0x1\",\n \"id\": 1 }"));
+        assert_eq!(transformed.status, 200u64);
+        assert!(transformed.headers.is_empty());
+        // Responses that only differ in whitespace or the order of their fields are equal
+        assert_eq!(
+            transformed.body,
+            transform(response("{\"id\":1,\"result\":\"0x1\"}")).body
+        );
+        assert_eq!(transformed.body, br#"{"id":1,"result":"0x1"}"#);
+    }
+
+    #[test]
+    fn transform_drops_error_data_and_non_json_bodies() {
+        let transformed = transform(response(
+            r#"{"id":1,"error":{"code":-32000,"message":"reverted","data":"request 7f3a"}}"#,
+        ));
+        assert_eq!(
+            transformed.body,
+            br#"{"error":{"code":-32000,"message":"reverted"},"id":1}"#
+        );
+        assert!(transform(response("<html>Bad Gateway</html>"))
+            .body
+            .is_empty());
+    }
+
+    #[test]
+    fn outcall_cost_grows_with_the_subnet_size() {
+        let api = RpcApi {
+            url: "https://rpc.example.com".to_string(),
+            headers: None,
+        };
+        let cost = |subnet_size| outcall_cost(&api, "{}", 1_000, subnet_size);
+        assert_eq!(
+            cost(DEFAULT_SUBNET_SIZE),
+            (3_000_000 + 60_000 * 13) * 13 + 400 * 13 * 25 + 800 * 13 * 1_000
+        );
+        assert!(cost(34) > cost(DEFAULT_SUBNET_SIZE));
+    }
+}
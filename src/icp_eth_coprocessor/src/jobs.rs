@@ -0,0 +1,496 @@
+use crate::error::CoprocessorError;
+use crate::events::DecodedEvent;
+use crate::evm_rpc;
+use crate::guard::RunGuard;
+use crate::handlers::{self, JobContext, JobResult};
+use crate::memory::{
+    update_entry, Job, JobStatus, LogId, Subscription, TransactionStatus, EVENT_ERRORS, JOBS,
+    PROCESSED_LOGS,
+};
+use crate::{chains, runtime, submit_result, subscriptions, sync, transactions};
+
+use std::cell::{Cell, RefCell};
+
+/// Maximum number of jobs handled in a single run of the queue.
+const MAX_JOBS_PER_RUN: usize = 10;
+
+/// Finished jobs are removed this long after their last update, in nanoseconds.
+const JOB_RETENTION: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+/// Maximum number of finished jobs removed in a single run of the queue.
+const MAX_PRUNED_PER_RUN: usize = 100;
+
+thread_local! {
+    static QUEUE_IN_PROGRESS: Cell<bool> = const { Cell::new(false) };
+
+    // The block number, log index and transaction hash of the last job picked up by a run. The
+    // next run continues after it, so that jobs that are postponed again and again do not keep
+    // the jobs behind them from being processed.
+    static QUEUE_CURSOR: RefCell<Option<(u128, u128, String)>> = const { RefCell::new(None) };
+}
+
+/// Adds a job for an ingested log to the queue. Returns false if the log is already queued or
+/// has been processed before, e.g. because an overlapping block range was fetched again.
+pub fn enqueue(
+    log_id: LogId,
+    subscription_id: u64,
+    chain_id: u64,
+    contract: String,
+    block_number: u128,
+    event: DecodedEvent,
+) -> bool {
+    if is_processed(&log_id) {
+        restore(&log_id);
+        return false;
+    }
+    JOBS.with(|jobs| {
+        let mut jobs = jobs.borrow_mut();
+        // A cancelled job is queued again if its log is included in the canonical chain again
+        if jobs
+            .get(&log_id)
+            .is_some_and(|job| job.status != JobStatus::Cancelled)
+        {
+            return false;
+        }
+        let now = runtime::time();
+        jobs.insert(
+            log_id,
+            Job {
+                subscription_id,
+                chain_id,
+                contract,
+                block_number,
+                event,
+                status: JobStatus::Discovered,
+                result: None,
+                transactions: Vec::new(),
+                error: None,
+                created_at: now,
+                updated_at: now,
+            },
+        );
+        true
+    })
+}
+
+/// Removes jobs from orphaned blocks of a chain that have not been submitted yet, so that they
+/// can be discovered again from the canonical chain. Returns the removed jobs.
+///
+/// Jobs whose callback is being sent or has been sent are kept together with their
+/// transactions, but flagged as orphaned. If their log is discovered again, they are restored.
+pub fn roll_back(chain_id: u64, from_block: u128) -> Vec<LogId> {
+    let (removed, flagged) = JOBS.with(|jobs| {
+        let mut jobs = jobs.borrow_mut();
+        let affected: Vec<(LogId, Job)> = jobs
+            .iter()
+            .filter(|(_, job)| job.chain_id == chain_id && job.block_number >= from_block)
+            .collect();
+        let mut removed = Vec::new();
+        let mut flagged = Vec::new();
+        for (log_id, mut job) in affected {
+            match job.status {
+                JobStatus::Discovered | JobStatus::Processing | JobStatus::ResultReady => {
+                    jobs.remove(&log_id);
+                    removed.push(log_id);
+                }
+                JobStatus::Submitting | JobStatus::Submitted => {
+                    job.status = JobStatus::Orphaned;
+                    jobs.insert(log_id.clone(), job);
+                    flagged.push(log_id);
+                }
+                _ => (),
+            }
+        }
+        (removed, flagged)
+    });
+    if !flagged.is_empty() {
+        runtime::print(format!(
+            "Callbacks of jobs {:?} were sent for logs of orphaned blocks",
+            flagged
+        ));
+    }
+    removed
+}
+
+/// Restores a job flagged as orphaned whose log has been found in the canonical chain again.
+fn restore(log_id: &LogId) {
+    let mut hashes = Vec::new();
+    let restored = update_if(log_id, JobStatus::Orphaned, |job| {
+        // A job without transactions is flagged while its callback is still being sent
+        job.status = if job.transactions.is_empty() {
+            JobStatus::Submitting
+        } else {
+            JobStatus::Submitted
+        };
+        hashes = job.transactions.clone();
+    });
+    if !restored {
+        return;
+    }
+    runtime::print(format!("Job {:?} restored", log_id));
+    // The outcome of the callback may have been recorded while the job was flagged
+    if let Some(status) = transactions::outcome(&hashes) {
+        confirm(log_id, status);
+    }
+}
+
+/// Cancels the job of a log that has been removed from the chain. If the callback has already
+/// been sent, or is being sent, the job is flagged as orphaned instead.
+pub fn cancel(log_id: &LogId) {
+    let found = update(log_id, |job| {
+        job.status = match job.status {
+            JobStatus::Submitting
+            | JobStatus::Submitted
+            | JobStatus::Confirmed
+            | JobStatus::Reverted
+            | JobStatus::Orphaned => JobStatus::Orphaned,
+            _ => JobStatus::Cancelled,
+        };
+    });
+    if !found && is_processed(log_id) {
+        runtime::print(format!(
+            "Log {:?} was removed after its callback had been sent",
+            log_id
+        ));
+    }
+}
+
+/// Drains the job queue: processes discovered jobs and submits their results.
+pub async fn process_queue() {
+    // Overlapping runs would pick up the same jobs twice
+    let _guard = match RunGuard::new(&QUEUE_IN_PROGRESS) {
+        Some(guard) => guard,
+        None => return,
+    };
+
+    // No other run is in progress, so jobs still marked as processing have been interrupted,
+    // e.g. by a trap or an upgrade. Processing is started over for those.
+    reset_interrupted();
+    prune();
+
+    // Jobs are handled in the order their logs were emitted. If they do not fit into a single
+    // run, the run starts after the cursor.
+    let mut pending: Vec<(LogId, Job)> = JOBS.with(|jobs| {
+        jobs.borrow()
+            .iter()
+            .filter(|(_, job)| matches!(job.status, JobStatus::Discovered | JobStatus::ResultReady))
+            .collect()
+    });
+    let position = |(log_id, job): &(LogId, Job)| {
+        (
+            job.block_number,
+            log_id.log_index,
+            log_id.transaction_hash.clone(),
+        )
+    };
+    pending.sort_by_key(position);
+    let start = match QUEUE_CURSOR.with(|cursor| cursor.borrow().clone()) {
+        Some(cursor) if pending.len() > MAX_JOBS_PER_RUN => pending
+            .iter()
+            .position(|job| position(job) > cursor)
+            .unwrap_or(0),
+        _ => 0,
+    };
+    pending.rotate_left(start);
+    pending.truncate(MAX_JOBS_PER_RUN);
+    if let Some(last) = pending.last() {
+        QUEUE_CURSOR.with(|cursor| *cursor.borrow_mut() = Some(position(last)));
+    }
+
+    for (log_id, job) in pending {
+        let subscription = match subscriptions::get(job.subscription_id) {
+            Some(subscription) => subscription,
+            None => {
+                fail(
+                    &log_id,
+                    CoprocessorError::InvalidTransaction(format!(
+                        "Subscription {} has been removed",
+                        job.subscription_id
+                    )),
+                );
+                continue;
+            }
+        };
+
+        let result = match (job.status, job.result.clone()) {
+            (JobStatus::ResultReady, Some(result)) => result,
+            _ => {
+                let requester = match requester(&log_id, &job).await {
+                    Ok(requester) => requester,
+                    // The job is picked up again by the next run
+                    Err(error) if error.is_transient() => {
+                        runtime::print(format!("Job {:?} postponed: {}", log_id, error));
+                        continue;
+                    }
+                    Err(error) => {
+                        fail(&log_id, error);
+                        continue;
+                    }
+                };
+                // The job may have been cancelled or rolled back while the requester was looked up
+                if !update_if(&log_id, job.status, |job| {
+                    job.status = JobStatus::Processing
+                }) {
+                    continue;
+                }
+                let result = match process_job(&log_id, &job, &subscription, requester).await {
+                    Ok(result) => result,
+                    Err(error) => {
+                        fail(&log_id, CoprocessorError::HandlerError(error));
+                        continue;
+                    }
+                };
+                // The job may have been cancelled or rolled back while it was processed
+                let still_queued = update_if(&log_id, JobStatus::Processing, |job| {
+                    job.status = JobStatus::ResultReady;
+                    job.result = Some(result.clone());
+                });
+                if !still_queued {
+                    continue;
+                }
+                result
+            }
+        };
+
+        // The job may have been rolled back or cancelled since the queue was read. Otherwise
+        // it is flagged as being submitted, so that a reorg does not remove it while its
+        // transaction is in flight.
+        if !update_if(&log_id, JobStatus::ResultReady, |job| {
+            job.status = JobStatus::Submitting
+        }) {
+            continue;
+        }
+
+        // The log is marked as processed before the transaction is sent, so that a trap or a
+        // retry can never result in a second callback for the same event.
+        if !mark_processed(&log_id, job.block_number) {
+            fail(
+                &log_id,
+                CoprocessorError::InvalidTransaction(
+                    "A callback for this log may have been sent already".to_string(),
+                ),
+            );
+            continue;
+        }
+
+        match submit_result(&log_id, &subscription, &result).await {
+            Ok(transaction_hash) => {
+                update(&log_id, |job| {
+                    job.status = match job.status {
+                        // The log was removed while the transaction was being sent
+                        JobStatus::Orphaned => JobStatus::Orphaned,
+                        _ => JobStatus::Submitted,
+                    };
+                    job.transactions.push(transaction_hash);
+                });
+            }
+            // Nothing has been broadcast, so the callback is sent again by the next run, unless
+            // the log has been removed in the meantime
+            Err(error) if error.is_transient() => {
+                runtime::print(format!("Job {:?} postponed: {}", log_id, error));
+                unmark_processed(&log_id);
+                update(&log_id, |job| {
+                    job.status = match job.status {
+                        JobStatus::Orphaned => JobStatus::Cancelled,
+                        _ => JobStatus::ResultReady,
+                    };
+                });
+            }
+            Err(error) => fail(&log_id, error),
+        }
+    }
+}
+
+/// Records the outcome of the transaction calling the callback of a job.
+pub fn confirm(log_id: &LogId, status: TransactionStatus) {
+    update_if(log_id, JobStatus::Submitted, |job| {
+        job.status = match status {
+            TransactionStatus::Reverted => JobStatus::Reverted,
+            _ => JobStatus::Confirmed,
+        };
+        if status == TransactionStatus::Reverted {
+            job.error = Some(CoprocessorError::CallbackReverted);
+        }
+    });
+}
+
+/// Adds a transaction that replaces the pending transaction of a job.
+pub fn add_transaction(log_id: &LogId, transaction_hash: String) {
+    update(log_id, |job| job.transactions.push(transaction_hash));
+}
+
+pub fn is_processed(log_id: &LogId) -> bool {
+    PROCESSED_LOGS.with(|logs| logs.borrow().contains_key(log_id))
+}
+
+/// Records that a callback is sent for the log. Returns false if that has happened before.
+fn mark_processed(log_id: &LogId, block_number: u128) -> bool {
+    PROCESSED_LOGS.with(|logs| {
+        logs.borrow_mut()
+            .insert(log_id.clone(), block_number)
+            .is_none()
+    })
+}
+
+fn unmark_processed(log_id: &LogId) {
+    PROCESSED_LOGS.with(|logs| logs.borrow_mut().remove(log_id));
+}
+
+/// Returns the sender of the transaction that emitted the log of the job.
+async fn requester(log_id: &LogId, job: &Job) -> Result<Option<String>, CoprocessorError> {
+    let chain = match chains::get(job.chain_id) {
+        Some(chain) => chain,
+        None => return Ok(None),
+    };
+    let receipt = evm_rpc::get_transaction_receipt(&chain, log_id.transaction_hash.clone()).await?;
+    Ok(receipt.map(|receipt| receipt.from))
+}
+
+/// Runs the handler registered for the event of the job.
+async fn process_job(
+    log_id: &LogId,
+    job: &Job,
+    subscription: &Subscription,
+    requester: Option<String>,
+) -> Result<JobResult, String> {
+    let handler = handlers::handler_for(subscription, &job.event.signature).ok_or(format!(
+        "No handler registered for {} of {}",
+        job.event.signature, job.contract
+    ))?;
+
+    let context = JobContext {
+        subscription_id: job.subscription_id,
+        chain_id: job.chain_id,
+        contract: job.contract.clone(),
+        block_number: job.block_number,
+        transaction_hash: log_id.transaction_hash.clone(),
+        log_index: log_id.log_index,
+        requester,
+    };
+
+    handler.handle(&context, &job.event).await
+}
+
+/// Picks up the jobs of a run that has been interrupted. Jobs whose callback was being sent
+/// are matched with the transactions recorded for their log, if any.
+fn reset_interrupted() {
+    let interrupted: Vec<(LogId, Job)> = JOBS.with(|jobs| {
+        jobs.borrow()
+            .iter()
+            .filter(|(_, job)| match job.status {
+                JobStatus::Processing | JobStatus::Submitting => true,
+                JobStatus::Orphaned => job.transactions.is_empty(),
+                _ => false,
+            })
+            .collect()
+    });
+    for (log_id, job) in interrupted {
+        let hashes = transactions::hashes_for(&log_id);
+        update(&log_id, |job| match job.status {
+            JobStatus::Processing => job.status = JobStatus::Discovered,
+            JobStatus::Submitting if hashes.is_empty() => {
+                job.status = JobStatus::Failed;
+                job.error = Some(CoprocessorError::InvalidTransaction(
+                    "Interrupted while the callback was sent, it may have been sent".to_string(),
+                ));
+            }
+            JobStatus::Submitting => {
+                job.status = JobStatus::Submitted;
+                job.transactions = hashes;
+            }
+            _ => job.transactions = hashes,
+        });
+        if job.status != JobStatus::Processing {
+            runtime::print(format!("Job {:?} was interrupted while submitting", log_id));
+        }
+    }
+}
+
+/// Removes finished jobs once they have been kept for `JOB_RETENTION`, together with their
+/// transactions and the processed mark of their log.
+///
+/// A job is only removed once its log can no longer be synced again, i.e. once its block is
+/// older than all tracked blocks of its chain. Otherwise the missing processed mark could lead
+/// to a second callback. Jobs whose transactions are still pending are kept as well. The
+/// highest pruned block of each chain is recorded, so that no subscription added later can sync
+/// the log again.
+///
+/// Logs that could not be decoded are removed after `JOB_RETENTION` as well. Those recorded by
+/// earlier versions of the canister carry no time and are removed right away.
+fn prune() {
+    let now = runtime::time();
+    let expired: Vec<(LogId, Job)> = JOBS.with(|jobs| {
+        jobs.borrow()
+            .iter()
+            .filter(|(_, job)| {
+                matches!(
+                    job.status,
+                    JobStatus::Confirmed
+                        | JobStatus::Reverted
+                        | JobStatus::Failed
+                        | JobStatus::Cancelled
+                        | JobStatus::Orphaned
+                ) && now.saturating_sub(job.updated_at) >= JOB_RETENTION
+            })
+            .filter(|(_, job)| {
+                sync::lowest_tracked_block(job.chain_id)
+                    .is_some_and(|lowest| job.block_number < lowest)
+            })
+            .filter(|(_, job)| !transactions::any_pending(&job.transactions))
+            .take(MAX_PRUNED_PER_RUN)
+            .collect()
+    });
+
+    let expired_errors: Vec<LogId> = EVENT_ERRORS.with(|errors| {
+        errors
+            .borrow()
+            .iter()
+            .filter(|(_, error)| {
+                now.saturating_sub(error.recorded_at.unwrap_or(0)) >= JOB_RETENTION
+            })
+            .map(|(log_id, _)| log_id)
+            .take(MAX_PRUNED_PER_RUN)
+            .collect()
+    });
+    for log_id in expired_errors {
+        EVENT_ERRORS.with(|errors| errors.borrow_mut().remove(&log_id));
+    }
+
+    for (log_id, job) in expired {
+        JOBS.with(|jobs| jobs.borrow_mut().remove(&log_id));
+        unmark_processed(&log_id);
+        transactions::remove(&job.transactions);
+        let _ = chains::update(job.chain_id, |chain| {
+            chain.pruned_block = chain.pruned_block.max(Some(job.block_number));
+        });
+    }
+}
+
+pub fn fail(log_id: &LogId, error: CoprocessorError) {
+    runtime::print(format!("Job {:?} failed: {}", log_id, error));
+    update(log_id, |job| {
+        job.status = JobStatus::Failed;
+        job.error = Some(error);
+    });
+}
+
+/// Like `update`, but only applies `f` if the job is still in the `expected` state.
+fn update_if(log_id: &LogId, expected: JobStatus, f: impl FnOnce(&mut Job)) -> bool {
+    let mut applied = false;
+    update(log_id, |job| {
+        if job.status == expected {
+            f(job);
+            applied = true;
+        }
+    });
+    applied
+}
+
+/// Applies `f` to the job and persists it. Returns false without doing anything if the job
+/// has been removed from the queue in the meantime, e.g. because of a reorg.
+fn update(log_id: &LogId, f: impl FnOnce(&mut Job)) -> bool {
+    update_entry(&JOBS, log_id, |job| {
+        f(job);
+        job.updated_at = runtime::time();
+    })
+}
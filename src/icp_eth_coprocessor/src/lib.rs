@@ -1,28 +1,65 @@
-use crate::evm_rpc::BlockTag;
-
 use ethers_core::types::U256;
-use ethers_core::utils::keccak256;
 
-use evm_rpc::SendRawTransactionStatus;
+use evm_rpc::{RpcServices, SendRawTransactionStatus};
 
+use ic_cdk::api::management_canister::http_request::{HttpResponse, TransformArgs};
 use ic_cdk_timers::set_timer_interval;
 
 use std::time::Duration;
 
+mod chains;
+mod error;
+mod events;
 mod evm_rpc;
 mod evm_signer;
+#[cfg(any(test, feature = "fake-evm-rpc"))]
+mod fake_evm_rpc;
+mod fees;
+mod guard;
+mod handlers;
+mod http_outcalls;
+mod jobs;
 mod memory;
-
-use memory::{CONFIG, STATE};
+mod nonce;
+mod rpc_backend;
+mod runtime;
+mod subscriptions;
+mod sync;
+#[cfg(test)]
+mod tests;
+mod transactions;
+
+use error::CoprocessorError;
+use events::AbiValue;
+use memory::{
+    Chain, ConfirmationPolicy, ConsensusSettings, EventError, FeeSettings, InitArgs, Job,
+    JobStatus, LogId, Network, RpcBackend, Subscription, Transaction, TransactionStatus, CONFIG,
+    EVENT_ERRORS, JOBS, STATE, TRANSACTIONS,
+};
+use subscriptions::SubscriptionArgs;
+
+/// Maximum number of entries returned by a single call of the paginated queries.
+const MAX_PAGE_SIZE: u64 = 100;
+
+/// Number of times a callback is signed and sent before giving up because its nonce has been
+/// used by transactions sent elsewhere.
+const MAX_SEND_ATTEMPTS: usize = 3;
 
 #[ic_cdk::init]
-fn init() {
+fn init(args: Option<InitArgs>) {
+    apply_init_args(args.unwrap_or_default());
+    handlers::register_handlers();
+    chains::init_default_chains();
+
     // Get tECDSA public key, calculate EVM address and store them in the state
     ic_cdk_timers::set_timer(Duration::ZERO, || {
         ic_cdk::spawn(async {
-            let pubkey = evm_signer::get_public_key().await;
+            let pubkey = evm_signer::get_public_key()
+                .await
+                .expect("Failed to get the public key");
 
-            let evm_address = evm_signer::pubkey_bytes_to_address(pubkey.as_slice());
+            let evm_address = evm_signer::pubkey_bytes_to_address(pubkey.as_slice())
+                .expect("Failed to derive the EVM address");
 
             STATE.with(|state| {
                 let mut s = state.borrow_mut().get().clone();
@@ -30,150 +67,334 @@ fn init() {
                 s.evm_address = Some(evm_address);
                 state.borrow_mut().set(s).expect("Failed to set state");
             });
+
+            nonce::sync_all().await;
         });
     });
 
-    set_timer_interval(Duration::from_secs(60), || ic_cdk::spawn(sync_logs()));
+    set_timer_interval(Duration::from_secs(60), || ic_cdk::spawn(sync::sync_logs()));
+    set_timer_interval(Duration::from_secs(10), || {
+        ic_cdk::spawn(jobs::process_queue())
+    });
+    set_timer_interval(Duration::from_secs(30), || {
+        ic_cdk::spawn(transactions::poll_receipts())
+    });
+    set_timer_interval(Duration::from_secs(60), || ic_cdk::spawn(nonce::sync_all()));
 }
 
 #[ic_cdk::post_upgrade]
-fn post_upgrade() {
-    set_timer_interval(Duration::from_secs(60), || ic_cdk::spawn(sync_logs()));
+fn post_upgrade(args: Option<InitArgs>) {
+    apply_init_args(args.unwrap_or_default());
+    handlers::register_handlers();
+    chains::init_default_chains();
+    subscriptions::migrate_legacy_contract();
+
+    // The nonces may have been used outside of the canister while it was stopped
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(nonce::sync_all()));
+
+    set_timer_interval(Duration::from_secs(60), || ic_cdk::spawn(sync::sync_logs()));
+    set_timer_interval(Duration::from_secs(10), || {
+        ic_cdk::spawn(jobs::process_queue())
+    });
+    set_timer_interval(Duration::from_secs(30), || {
+        ic_cdk::spawn(transactions::poll_receipts())
+    });
+    set_timer_interval(Duration::from_secs(60), || ic_cdk::spawn(nonce::sync_all()));
 }
 
-#[ic_cdk::update]
-fn set_contract(contract: String) {
+fn apply_init_args(args: InitArgs) {
     CONFIG.with(|config| {
         let mut c = config.borrow().get().clone();
-        c.evm_contract = Some(contract);
-        let _ = config.borrow_mut().set(c);
+        if let Some(evm_rpc_canister) = args.evm_rpc_canister {
+            c.evm_rpc_canister = Some(evm_rpc_canister);
+        }
+        if let Some(subnet_size) = args.subnet_size {
+            c.subnet_size = Some(subnet_size);
+        }
+        config.borrow_mut().set(c).expect("Failed to set config");
     });
 }
 
-#[ic_cdk::query]
-fn get_evm_address() -> String {
-    let address = STATE.with(|state| {
-        let s = state.borrow();
-        let s = s.get();
-        s.evm_address.clone()
-    });
-    //TODO: return error if address is None
-    address.unwrap_or("Not initialized".to_string())
+#[ic_cdk::update(guard = "caller_is_controller")]
+async fn add_subscription(args: SubscriptionArgs) -> Result<u64, CoprocessorError> {
+    subscriptions::add(args).await
 }
 
-async fn sync_logs() {
-    let config = CONFIG.with(|config| config.borrow().get().clone());
-    let state = STATE.with(|state| state.borrow().get().clone());
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn remove_subscription(id: u64) -> Result<(), CoprocessorError> {
+    subscriptions::remove(id).map_err(CoprocessorError::InvalidArgument)
+}
 
-    if config.evm_contract.is_none() {
-        return;
-    } else {
-        let logs = evm_rpc::get_logs(
-            config.evm_network.clone(),
-            [config.evm_contract.clone().unwrap()].to_vec(),
-            None,
-            state.evm_block_height+1,
-            BlockTag::Latest,
-        )
-        .await;
-
-        for (index, event) in logs.iter().enumerate() {
-            let job_id = hex_to_u64(&event.data).unwrap_or(0);
-            process_job(job_id).await;
-
-            // Update the state with the latest block number
-            if index == logs.len() - 1 {
-                STATE.with(|state| {
-                    let mut s = state.borrow_mut().get().clone();
-                    s.evm_block_height = event.blockNumber.unwrap();
-                    state.borrow_mut().set(s).expect("Failed to set state");
-                });
-            }
-        }
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn pause_subscription(id: u64) -> Result<(), CoprocessorError> {
+    subscriptions::set_paused(id, true).map_err(CoprocessorError::InvalidArgument)
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn resume_subscription(id: u64) -> Result<(), CoprocessorError> {
+    subscriptions::set_paused(id, false).map_err(CoprocessorError::InvalidArgument)
+}
+
+#[ic_cdk::query]
+fn get_subscriptions() -> Vec<(u64, Subscription)> {
+    subscriptions::list()
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+async fn add_chain(network: Network) -> Result<u64, CoprocessorError> {
+    let chain_id = chains::add(network).map_err(CoprocessorError::InvalidArgument)?;
+    if let Err(error) = nonce::sync(chain_id).await {
+        // The nonce is synced again by the periodic sync
+        runtime::print(format!(
+            "Chain {}: failed to sync nonce: {}",
+            chain_id, error
+        ));
     }
+    Ok(chain_id)
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn remove_chain(chain_id: u64) -> Result<(), CoprocessorError> {
+    chains::remove(chain_id).map_err(CoprocessorError::InvalidArgument)
+}
+
+/// Returns the chains. The values of the HTTP headers of custom providers are redacted.
+#[ic_cdk::query]
+fn get_chains() -> Vec<(u64, Chain)> {
+    chains::list_redacted()
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_confirmation_policy(
+    chain_id: u64,
+    policy: ConfirmationPolicy,
+) -> Result<(), CoprocessorError> {
+    chains::set_confirmation_policy(chain_id, policy).map_err(CoprocessorError::InvalidArgument)
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_max_block_range(chain_id: u64, max_block_range: u64) -> Result<(), CoprocessorError> {
+    chains::set_max_block_range(chain_id, max_block_range)
+        .map_err(CoprocessorError::InvalidArgument)
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_gas_margin(chain_id: u64, percent: u64) -> Result<(), CoprocessorError> {
+    chains::set_gas_margin(chain_id, percent).map_err(CoprocessorError::InvalidArgument)
 }
 
-async fn process_job(job_id: u64) {
-    ic_cdk::print(format!("Processing job {}", job_id));
-    let result = "42";
-    submit_result(job_id, result).await;
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_fee_settings(chain_id: u64, fees: FeeSettings) -> Result<(), CoprocessorError> {
+    chains::set_fees(chain_id, fees).map_err(CoprocessorError::InvalidArgument)
 }
 
-async fn submit_result(job_id: u64, result: &str) {
-    let config = CONFIG.with(|config| config.borrow().get().clone());
-    let state = STATE.with(|state| state.borrow().get().clone());
+/// Sets how differing responses of the providers of a chain are resolved.
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_consensus_settings(
+    chain_id: u64,
+    consensus: ConsensusSettings,
+) -> Result<(), CoprocessorError> {
+    chains::set_consensus(chain_id, consensus).map_err(CoprocessorError::InvalidArgument)
+}
 
-    
-    //TODO: Should probably be hardcoded. Recomputing the hash every time is unnecessary
-    let function_signature = "callback(string)";
-    let argument = result.to_string();
+/// Replaces the providers of a chain, `null` restores the default providers of its network.
+/// Restricted to controllers, as custom providers may carry API keys in their headers.
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_rpc_services(chain_id: u64, services: Option<RpcServices>) -> Result<(), CoprocessorError> {
+    chains::set_rpc_services(chain_id, services).map_err(CoprocessorError::InvalidArgument)
+}
 
-    let mut data = keccak256(function_signature).as_ref()[0..4].to_vec();
-    data.extend(ethers_core::abi::AbiEncode::encode(argument));
+/// Selects whether the providers of a chain are called through the EVM RPC canister or with
+/// HTTPS outcalls of this canister. HTTPS outcalls require custom providers.
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_rpc_backend(chain_id: u64, backend: RpcBackend) -> Result<(), CoprocessorError> {
+    chains::set_rpc_backend(chain_id, backend).map_err(CoprocessorError::InvalidArgument)
+}
 
-    //TODO: Proper fee estimation
-    let fee_history = evm_rpc::fee_history(config.evm_network.clone(), 10, BlockTag::Latest, None).await;
-    let base_fee = fee_history.baseFeePerGas.last().unwrap();
-    // let priority_fees: Vec<_> = fee_history.reward.iter().flatten().collect();
-    // let median_priority_fee = priority_fees[priority_fees.len() / 2];
+/// Removes the non-deterministic parts of the responses to HTTPS outcalls.
+#[ic_cdk::query]
+fn transform_rpc_response(args: TransformArgs) -> HttpResponse {
+    http_outcalls::transform(args.response)
+}
 
-    let max_priority_fee_per_gas = 100;
-    let max_fee_per_gas = base_fee.saturating_add(max_priority_fee_per_gas);
+#[ic_cdk::query]
+fn get_evm_address() -> String {
+    let address = STATE.with(|state| {
+        let s = state.borrow();
+        let s = s.get();
+        s.evm_address.clone()
+    });
+    //TODO: return error if address is None
+    address.unwrap_or("Not initialized".to_string())
+}
 
-    //TODO: Set chain_id based on evm_network
-    let req = evm_signer::SignRequest {
-        chain_id: 11155111,
-        to: config.evm_contract.clone().unwrap(),
-        gas: U256::from(50000),
-        max_fee_per_gas: U256::from(max_fee_per_gas),
-        max_priority_fee_per_gas: U256::from(max_priority_fee_per_gas),
-        data: Some(data),
-        value: U256::from(0),
-        nonce: U256::from(state.nonce),
-    };
+/// Returns the logs that could not be decoded into jobs. Paginated like `get_jobs` and removed
+/// after a week as well.
+#[ic_cdk::query]
+fn get_event_errors(start_after: Option<LogId>, limit: Option<u64>) -> Vec<(LogId, EventError)> {
+    memory::page(&EVENT_ERRORS, start_after, page_size(limit))
+}
 
-    let tx = evm_signer::sign_transaction(req).await;
+/// Returns up to `limit` jobs, by default `MAX_PAGE_SIZE`, ordered by their log ID and starting
+/// after `start_after`. Finished jobs are removed after a week.
+#[ic_cdk::query]
+fn get_jobs(start_after: Option<LogId>, limit: Option<u64>) -> Vec<(LogId, Job)> {
+    memory::page(&JOBS, start_after, page_size(limit))
+}
 
-    let status = evm_rpc::send_raw_transaction(config.evm_network.clone(), tx.clone()).await;
+/// Returns the callback transactions together with their receipt data once they have been
+/// included in a block. Paginated like `get_jobs`, ordered by transaction hash.
+#[ic_cdk::query]
+fn get_transactions(start_after: Option<String>, limit: Option<u64>) -> Vec<(String, Transaction)> {
+    memory::page(&TRANSACTIONS, start_after, page_size(limit))
+}
 
-    ic_cdk::print(format!("Transaction sent: {:?}", tx));
+fn page_size(limit: Option<u64>) -> usize {
+    limit.unwrap_or(MAX_PAGE_SIZE).min(MAX_PAGE_SIZE) as usize
+}
 
-    match status {
-        SendRawTransactionStatus::Ok => {
-            ic_cdk::print("Transaction sent");
-            STATE.with(|state| {
-                let mut s = state.borrow_mut().get().clone();
-                s.nonce += 1;
-                state.borrow_mut().set(s).expect("Failed to set state");
-            });
-        }
-        SendRawTransactionStatus::NonceTooLow => {
-            ic_cdk::print("Nonce too low");
-        }
-        SendRawTransactionStatus::NonceTooHigh => {
-            ic_cdk::print("Nonce too high");
-        }
-        SendRawTransactionStatus::InsufficientFunds => {
-            ic_cdk::print("Insufficient funds");
-        }
-    }
+/// Returns the jobs whose log was removed from the chain after the callback had been sent.
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn get_orphaned_jobs() -> Vec<(LogId, Job)> {
+    JOBS.with(|jobs| {
+        jobs.borrow()
+            .iter()
+            .filter(|(_, job)| job.status == JobStatus::Orphaned)
+            .collect()
+    })
 }
 
-fn hex_to_u64(hex_str: &str) -> Result<u64, hex::FromHexError> {
-    // Remove the 0x prefix if it exists
-    let clean_hex_str = if hex_str.starts_with("0x") {
-        &hex_str[2..]
+fn caller_is_controller() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
     } else {
-        hex_str
-    };
-
-    let bytes = hex::decode(clean_hex_str)?;
-    let mut result: u64 = 0;
+        Err("Only controllers can call this method".to_string())
+    }
+}
 
-    // Iterate over the bytes and construct the number
-    for byte in bytes {
-        result = (result << 8) | (byte as u64);
+/// Sends a transaction calling the callback of the subscription with the result of a job.
+/// Returns the hash of the transaction.
+///
+/// Errors that are transient according to `CoprocessorError::is_transient` guarantee that no
+/// transaction has been broadcast, so the callback can be submitted again.
+async fn submit_result(
+    log_id: &LogId,
+    subscription: &Subscription,
+    result: &[AbiValue],
+) -> Result<String, CoprocessorError> {
+    let chain = chains::get(subscription.chain_id).ok_or(CoprocessorError::InvalidTransaction(
+        format!("Chain {} not found", subscription.chain_id),
+    ))?;
+
+    let callback = events::parse_function(&subscription.callback)
+        .map_err(CoprocessorError::InvalidTransaction)?;
+    let data =
+        events::encode_call(&callback, result).map_err(CoprocessorError::InvalidTransaction)?;
+
+    let from = STATE
+        .with(|state| state.borrow().get().evm_address.clone())
+        .ok_or(CoprocessorError::SigningError(
+            "EVM address not initialized".to_string(),
+        ))?;
+    let estimate =
+        evm_rpc::estimate_gas(&chain, from, subscription.contract.clone(), &data).await?;
+    if estimate > subscription.max_gas {
+        return Err(CoprocessorError::InvalidTransaction(format!(
+            "Gas estimate of {} exceeds the limit of {}",
+            estimate, subscription.max_gas
+        )));
+    }
+    let gas = (estimate.saturating_mul(100 + chain.gas_margin_percent() as u128) / 100)
+        .min(subscription.max_gas);
+
+    let fees = fees::estimate(&chain).await?;
+
+    // A transaction rejected because of its nonce is signed again with a new nonce, after the
+    // nonces of the chain have been reconciled with the network
+    for _ in 0..MAX_SEND_ATTEMPTS {
+        let nonce =
+            nonce::reserve(subscription.chain_id).map_err(CoprocessorError::InvalidTransaction)?;
+
+        let req = evm_signer::SignRequest {
+            chain_id: chain.network.chain_id(),
+            to: subscription.contract.clone(),
+            gas: U256::from(gas),
+            max_fee_per_gas: U256::from(fees.max_fee_per_gas),
+            max_priority_fee_per_gas: U256::from(fees.max_priority_fee_per_gas),
+            data: Some(data.clone()),
+            value: U256::from(0),
+            nonce: U256::from(nonce),
+        };
+
+        let tx = match evm_signer::sign_transaction(req).await {
+            Ok(tx) => tx,
+            Err(error) => {
+                nonce::release(subscription.chain_id, nonce);
+                return Err(error);
+            }
+        };
+
+        let status = evm_rpc::send_raw_transaction(&chain, tx.clone()).await;
+
+        runtime::print(format!("Transaction sent: {:?}", tx));
+
+        match status {
+            // If the status is unknown, the transaction may have reached the network. It is
+            // tracked like an accepted one: either its receipt is found, or it is replaced once
+            // it is considered stuck.
+            Ok(SendRawTransactionStatus::Ok) | Err(_) => {
+                let hash = evm_signer::transaction_hash(&tx);
+                match status {
+                    Ok(_) => runtime::print(format!("Transaction {} sent", hash)),
+                    Err(error) => {
+                        runtime::print(format!("Status of transaction {} unknown: {}", hash, error))
+                    }
+                }
+                transactions::record(
+                    hash.clone(),
+                    Transaction {
+                        chain_id: subscription.chain_id,
+                        log_id: log_id.clone(),
+                        replaces: None,
+                        to: subscription.contract.clone(),
+                        data: data.clone(),
+                        nonce,
+                        gas_limit: gas,
+                        max_fee_per_gas: fees.max_fee_per_gas,
+                        max_priority_fee_per_gas: fees.max_priority_fee_per_gas,
+                        status: TransactionStatus::Pending,
+                        sent_at: runtime::time(),
+                        block_number: None,
+                        gas_used: None,
+                        effective_gas_price: None,
+                    },
+                );
+                return Ok(hash);
+            }
+            Ok(SendRawTransactionStatus::NonceTooLow) => {
+                runtime::print(format!("Nonce {} too low", nonce));
+                nonce::release(subscription.chain_id, nonce);
+                nonce::sync(subscription.chain_id).await?;
+            }
+            // A lower nonce is missing from the network. It is only handed out again once the
+            // periodic sync has reported it for a while, so the callback is sent by a later run.
+            Ok(SendRawTransactionStatus::NonceTooHigh) => {
+                runtime::print(format!("Nonce {} too high", nonce));
+                nonce::release(subscription.chain_id, nonce);
+                nonce::sync(subscription.chain_id).await?;
+                return Err(CoprocessorError::NonceTooHigh { nonce });
+            }
+            Ok(SendRawTransactionStatus::InsufficientFunds) => {
+                nonce::release(subscription.chain_id, nonce);
+                return Err(CoprocessorError::InvalidTransaction(
+                    "Insufficient funds".to_string(),
+                ));
+            }
+        }
     }
 
-    Ok(result)
+    Err(CoprocessorError::InvalidTransaction(format!(
+        "Transaction rejected {} times because of its nonce",
+        MAX_SEND_ATTEMPTS
+    )))
 }
@@ -1,21 +1,82 @@
-use candid::{CandidType, Decode, Deserialize, Encode};
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
 
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::storable::Bound;
-use ic_stable_structures::{DefaultMemoryImpl, StableCell, Storable};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
 
-use std::{borrow::Cow, cell::RefCell};
+use std::{borrow::Cow, cell::RefCell, ops::Bound as RangeBound, thread::LocalKey};
+
+use crate::error::CoprocessorError;
+use crate::events::{AbiValue, DecodedEvent};
+use crate::evm_rpc::{EthMainnetService, EthSepoliaService, RpcApi, RpcService, RpcServices};
 
 type VMem = VirtualMemory<DefaultMemoryImpl>;
 
 const MAX_VALUE_SIZE: u32 = 100;
 
+// A transaction hash (66 characters) plus the log index and the Candid header.
+const MAX_LOG_ID_SIZE: u32 = 128;
+
+const DEFAULT_MAX_BLOCK_RANGE: u64 = 500;
+
+const DEFAULT_GAS_MARGIN_PERCENT: u64 = 20;
+
+// A larger margin only wastes the gas limit, the callbacks are still bounded by `max_gas`.
+pub const MAX_GAS_MARGIN_PERCENT: u64 = 100;
+
+const DEFAULT_REWARD_PERCENTILE: u8 = 50;
+
+// Covers two blocks of maximal base fee increases (12.5% each).
+const DEFAULT_BASE_FEE_MULTIPLIER_PERCENT: u64 = 127;
+
+const DEFAULT_MIN_PRIORITY_FEE: u128 = 100;
+
+const DEFAULT_REPLACE_AFTER_SECS: u64 = 180;
+
+pub const ETH_MAINNET_CHAIN_ID: u64 = 1;
+pub const ETH_SEPOLIA_CHAIN_ID: u64 = 11155111;
+
+/// Determines up to which block logs are considered final enough to be ingested.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ConfirmationPolicy {
+    /// Only ingest logs from blocks that are at least this many blocks behind the latest block.
+    Depth(u64),
+    /// Ingest logs up to the block tagged `safe` by the network.
+    Safe,
+    /// Ingest logs up to the block tagged `finalized` by the network.
+    Finalized,
+}
+
+impl ConfirmationPolicy {
+    /// The policy used for a network if none has been configured explicitly.
+    pub fn default_for(network: &Network) -> Self {
+        match network {
+            Network::EthMainnet => ConfirmationPolicy::Finalized,
+            _ => ConfirmationPolicy::Depth(12),
+        }
+    }
+}
+
 #[derive(CandidType, Deserialize, Clone)]
 
 pub struct Config {
+    /// The contract set with `set_contract` before subscriptions were introduced. It is turned
+    /// into a subscription on upgrade.
     pub evm_contract: Option<String>,
     pub ecdsa_key_name: String,
-    pub evm_network: String,
+    /// Replaces the EVM RPC canister deployed on the IC, e.g. by a local mock or a fork.
+    pub evm_rpc_canister: Option<Principal>,
+    /// The number of nodes of the subnet the canister runs on, which determines the cost of
+    /// HTTPS outcalls. Defaults to 13, the size of an application subnet.
+    pub subnet_size: Option<u32>,
+}
+
+/// The arguments of the installation and of upgrades of the canister. Fields that are `None`
+/// leave the configuration unchanged.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct InitArgs {
+    pub evm_rpc_canister: Option<Principal>,
+    pub subnet_size: Option<u32>,
 }
 
 impl Storable for Config {
@@ -37,8 +98,8 @@ impl Storable for Config {
 pub struct State {
     pub ecdsa_pub_key: Option<Vec<u8>>,
     pub evm_address: Option<String>,
-    pub evm_block_height: u128,
-    pub nonce: u128,
+    /// The last block synced for `Config::evm_contract`.
+    pub evm_block_height: Option<u128>,
 }
 
 impl Storable for State {
@@ -56,6 +117,435 @@ impl Storable for State {
     };
 }
 
+/// An EVM chain reachable through the EVM RPC canister.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Network {
+    EthMainnet,
+    EthSepolia,
+    /// Any other EVM chain, reached through the given JSON-RPC endpoints.
+    Custom {
+        chain_id: u64,
+        rpc_urls: Vec<String>,
+    },
+}
+
+impl Network {
+    pub fn chain_id(&self) -> u64 {
+        match self {
+            Network::EthMainnet => ETH_MAINNET_CHAIN_ID,
+            Network::EthSepolia => ETH_SEPOLIA_CHAIN_ID,
+            Network::Custom { chain_id, .. } => *chain_id,
+        }
+    }
+
+    /// The services of the EVM RPC canister used to talk to the chain.
+    pub fn rpc_services(&self) -> RpcServices {
+        match self {
+            Network::EthMainnet => RpcServices::EthMainnet(None),
+            Network::EthSepolia => RpcServices::EthSepolia(Some(vec![EthSepoliaService::Alchemy])),
+            Network::Custom { chain_id, rpc_urls } => RpcServices::Custom {
+                chainId: *chain_id,
+                services: rpc_urls
+                    .iter()
+                    .map(|url| RpcApi {
+                        url: url.clone(),
+                        headers: None,
+                    })
+                    .collect(),
+            },
+        }
+    }
+
+    /// The single service used for calls that are not replicated across providers, such as
+    /// raw JSON-RPC requests.
+    pub fn rpc_service(&self) -> RpcService {
+        match self {
+            Network::EthMainnet => RpcService::EthMainnet(EthMainnetService::PublicNode),
+            Network::EthSepolia => RpcService::EthSepolia(EthSepoliaService::Alchemy),
+            Network::Custom { rpc_urls, .. } => RpcService::Custom(RpcApi {
+                url: rpc_urls.first().cloned().unwrap_or_default(),
+                headers: None,
+            }),
+        }
+    }
+}
+
+/// Fee settings for the callback transactions sent to a chain. All fees are in wei per gas.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FeeSettings {
+    /// The percentile of the priority fees paid in recent blocks that is offered as tip.
+    pub reward_percentile: u8,
+    /// Applied to the base fee of the next block, in percent, so that the transaction can still
+    /// be included if the base fee rises in the meantime.
+    pub base_fee_multiplier_percent: u64,
+    pub min_priority_fee_per_gas: u128,
+    pub max_priority_fee_per_gas: Option<u128>,
+    /// Transactions are not sent while the base fee of the next block plus the tip exceeds it.
+    pub max_fee_per_gas: Option<u128>,
+    /// Transactions that are still pending after this many seconds are replaced by a
+    /// transaction with higher fees.
+    pub replace_after_secs: u64,
+}
+
+impl Default for FeeSettings {
+    fn default() -> Self {
+        FeeSettings {
+            reward_percentile: DEFAULT_REWARD_PERCENTILE,
+            base_fee_multiplier_percent: DEFAULT_BASE_FEE_MULTIPLIER_PERCENT,
+            min_priority_fee_per_gas: DEFAULT_MIN_PRIORITY_FEE,
+            max_priority_fee_per_gas: None,
+            max_fee_per_gas: None,
+            replace_after_secs: DEFAULT_REPLACE_AFTER_SECS,
+        }
+    }
+}
+
+/// How the responses of the providers of a replicated call are combined into one result when
+/// they differ.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ConsensusPolicy {
+    /// All providers must return the same result.
+    Unanimous,
+    /// At least `min` providers must return the same result, and no other result may be
+    /// returned as often.
+    Majority { min: u8 },
+    /// The first successful response, in the order of the providers, is used.
+    FirstSuccess,
+}
+
+/// Consensus policies of the calls to the providers of a chain.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ConsensusSettings {
+    /// Applies to all calls reading the state of the chain.
+    pub reads: ConsensusPolicy,
+    /// Applies to `eth_sendRawTransaction`. Providers that have already received a transaction
+    /// from a peer report its nonce as too low, so a single provider accepting it is enough.
+    pub send_raw_transaction: ConsensusPolicy,
+}
+
+impl Default for ConsensusSettings {
+    fn default() -> Self {
+        ConsensusSettings {
+            reads: ConsensusPolicy::Unanimous,
+            send_raw_transaction: ConsensusPolicy::FirstSuccess,
+        }
+    }
+}
+
+/// How the canister reaches the JSON-RPC providers of a chain.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum RpcBackend {
+    /// Through the EVM RPC canister, which also knows the default providers of the networks.
+    EvmRpcCanister,
+    /// With HTTPS outcalls of the canister itself. Only supports custom providers.
+    HttpOutcalls,
+}
+
+/// A chain the canister syncs logs from and sends callbacks to.
+///
+/// Settings that did not exist when the chain registry was introduced are optional, so that
+/// chains stored by earlier versions of the canister can still be decoded after an upgrade.
+/// `None` selects the default.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Chain {
+    pub network: Network,
+    pub confirmation_policy: Option<ConfirmationPolicy>,
+    pub max_block_range: Option<u64>,
+    /// Added to the gas estimate of a callback, in percent.
+    pub gas_margin_percent: Option<u64>,
+    pub fees: Option<FeeSettings>,
+    pub consensus: Option<ConsensusSettings>,
+    /// The next nonce that has never been handed out on this chain.
+    pub nonce: u128,
+    /// Nonces below `nonce` that have not been used by a transaction known to the network.
+    /// They are handed out again before `nonce`, lowest first.
+    pub nonce_gaps: Option<Vec<u128>>,
+    /// The last confirmed block whose logs have been requested for all subscriptions.
+    pub synced_block: Option<u128>,
+    /// The error that stopped the last sync of the chain, if any.
+    pub sync_error: Option<CoprocessorError>,
+    /// Replaces the default providers of the network, e.g. to use custom providers with API
+    /// keys.
+    pub rpc_services: Option<RpcServices>,
+    pub rpc_backend: Option<RpcBackend>,
+    /// The highest block of a job that has been pruned together with the processed mark of its
+    /// log. Subscriptions cannot start at or before it, as those logs would be processed again.
+    pub pruned_block: Option<u128>,
+}
+
+impl Chain {
+    pub fn new(network: Network) -> Self {
+        Chain {
+            network,
+            confirmation_policy: None,
+            max_block_range: None,
+            gas_margin_percent: None,
+            fees: None,
+            consensus: None,
+            nonce: 0,
+            nonce_gaps: None,
+            synced_block: None,
+            sync_error: None,
+            rpc_services: None,
+            rpc_backend: None,
+            pruned_block: None,
+        }
+    }
+
+    pub fn confirmation_policy(&self) -> ConfirmationPolicy {
+        self.confirmation_policy
+            .clone()
+            .unwrap_or_else(|| ConfirmationPolicy::default_for(&self.network))
+    }
+
+    /// The maximum number of blocks requested in a single `eth_getLogs` call.
+    pub fn max_block_range(&self) -> u64 {
+        self.max_block_range
+            .unwrap_or(DEFAULT_MAX_BLOCK_RANGE)
+            .max(1)
+    }
+
+    pub fn gas_margin_percent(&self) -> u64 {
+        self.gas_margin_percent
+            .unwrap_or(DEFAULT_GAS_MARGIN_PERCENT)
+            .min(MAX_GAS_MARGIN_PERCENT)
+    }
+
+    pub fn fees(&self) -> FeeSettings {
+        self.fees.clone().unwrap_or_default()
+    }
+
+    pub fn consensus(&self) -> ConsensusSettings {
+        self.consensus.clone().unwrap_or_default()
+    }
+
+    pub fn nonce_gaps(&self) -> &[u128] {
+        self.nonce_gaps.as_deref().unwrap_or_default()
+    }
+
+    pub fn nonce_gaps_mut(&mut self) -> &mut Vec<u128> {
+        self.nonce_gaps.get_or_insert_with(Vec::new)
+    }
+
+    pub fn rpc_backend(&self) -> RpcBackend {
+        self.rpc_backend
+            .clone()
+            .unwrap_or(RpcBackend::EvmRpcCanister)
+    }
+
+    /// The services of the EVM RPC canister used to talk to the chain.
+    pub fn rpc_services(&self) -> RpcServices {
+        self.rpc_services
+            .clone()
+            .unwrap_or_else(|| self.network.rpc_services())
+    }
+
+    /// The first of the configured services, for calls that are not replicated.
+    pub fn rpc_service(&self) -> RpcService {
+        let service = match self.rpc_services() {
+            RpcServices::EthMainnet(Some(services)) => {
+                services.into_iter().next().map(RpcService::EthMainnet)
+            }
+            RpcServices::EthSepolia(Some(services)) => {
+                services.into_iter().next().map(RpcService::EthSepolia)
+            }
+            RpcServices::Custom { services, .. } => {
+                services.into_iter().next().map(RpcService::Custom)
+            }
+            _ => None,
+        };
+        service.unwrap_or_else(|| self.network.rpc_service())
+    }
+}
+
+impl Storable for Chain {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Uniquely identifies a log by the transaction that emitted it and its index in the block.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LogId {
+    pub transaction_hash: String,
+    pub log_index: u128,
+}
+
+impl Storable for LogId {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_LOG_ID_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+/// A log that could not be turned into a job.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EventError {
+    pub block_number: Option<u128>,
+    pub error: String,
+    /// When the error was recorded, in nanoseconds since the Unix epoch. Errors are removed
+    /// together with the finished jobs.
+    pub recorded_at: Option<u64>,
+}
+
+impl Storable for EventError {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A contract event the canister listens to.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Subscription {
+    pub chain_id: u64,
+    pub contract: String,
+    /// The human readable ABI of the event, e.g. `event NewJob(uint256 job_id)`.
+    pub event: String,
+    /// Additional filters for the indexed event parameters (topic 1 to 3). Each entry lists the
+    /// accepted values for its position, an empty entry matches any value.
+    pub topic_filters: Vec<Vec<String>>,
+    pub start_block: u128,
+    /// The last block whose logs have been ingested.
+    pub cursor: u128,
+    /// The human readable ABI of the function receiving the results, e.g.
+    /// `function callback(string)`.
+    pub callback: String,
+    /// The name of the handler processing the jobs. If not set, the handler registered for the
+    /// contract and event is used.
+    pub handler: Option<String>,
+    /// Callbacks whose gas estimate exceeds this limit are not sent.
+    pub max_gas: u128,
+    pub paused: bool,
+}
+
+impl Storable for Subscription {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobStatus {
+    /// The log has been ingested, the job has not been started yet.
+    Discovered,
+    /// The job is being processed.
+    Processing,
+    /// The result has been computed but not been sent to the contract yet.
+    ResultReady,
+    /// The transaction calling the callback is being signed and sent.
+    Submitting,
+    /// The transaction calling the callback has been sent.
+    Submitted,
+    /// The transaction calling the callback has been included in a block.
+    Confirmed,
+    /// The transaction calling the callback has been included in a block, but reverted.
+    Reverted,
+    Failed,
+    /// The log has been removed from the chain before a callback was sent.
+    Cancelled,
+    /// The log has been removed from the chain after a callback had been sent, or while it was
+    /// being sent.
+    Orphaned,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Job {
+    pub subscription_id: u64,
+    pub chain_id: u64,
+    pub contract: String,
+    pub block_number: u128,
+    pub event: DecodedEvent,
+    pub status: JobStatus,
+    pub result: Option<Vec<AbiValue>>,
+    /// The hashes of the transactions calling the callback. Every transaction replaces the
+    /// one before it with higher fees.
+    pub transactions: Vec<String>,
+    pub error: Option<CoprocessorError>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Storable for Job {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransactionStatus {
+    /// The transaction has been accepted by the network, but no receipt is available yet.
+    Pending,
+    Confirmed,
+    Reverted,
+    /// Another transaction with the same nonce has been included instead.
+    Replaced,
+}
+
+/// A transaction calling the callback of a job.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Transaction {
+    pub chain_id: u64,
+    /// The log that created the job.
+    pub log_id: LogId,
+    /// The transaction that has been replaced by this one, because it was stuck.
+    pub replaces: Option<String>,
+    pub to: String,
+    pub data: Vec<u8>,
+    pub nonce: u128,
+    pub gas_limit: u128,
+    pub max_fee_per_gas: u128,
+    pub max_priority_fee_per_gas: u128,
+    pub status: TransactionStatus,
+    pub sent_at: u64,
+    pub block_number: Option<u128>,
+    pub gas_used: Option<u128>,
+    pub effective_gas_price: Option<u128>,
+}
+
+impl Storable for Transaction {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
 thread_local! {
 
     // The memory manager is used for simulating multiple memories. Given a `MemoryId` it can
@@ -70,7 +560,8 @@ thread_local! {
                 Config {
                     evm_contract: None,
                     ecdsa_key_name: "dfx_test_key_1".to_string(),
-                    evm_network: "EthSepolia".to_string(),
+                    evm_rpc_canister: None,
+                    subnet_size: None,
                 }
         ).unwrap()
     );
@@ -81,10 +572,116 @@ thread_local! {
             State {
                 ecdsa_pub_key: None,
                 evm_address: None,
-                evm_block_height: 5552046,
-                nonce: 0,
+                evm_block_height: None,
             }
     ).unwrap()
 );
 
+    // The ID of the next subscription. IDs are never reused, so that the jobs of a removed
+    // subscription are not attributed to a later one.
+    pub static NEXT_SUBSCRIPTION_ID: RefCell<StableCell<u64, VMem>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
+            0,
+        ).unwrap()
+    );
+
+    // Logs that failed to decode, together with the error.
+    pub static EVENT_ERRORS: RefCell<StableBTreeMap<LogId, EventError, VMem>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
+        )
+    );
+
+    // The job queue, keyed by the log that created the job.
+    pub static JOBS: RefCell<StableBTreeMap<LogId, Job, VMem>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+        )
+    );
+
+    // Logs for which a callback transaction has been sent, mapped to their block number.
+    // Jobs are never submitted twice for the same log. Entries are removed together with their
+    // job once the log is too old to be synced again.
+    pub static PROCESSED_LOGS: RefCell<StableBTreeMap<LogId, u128, VMem>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))),
+        )
+    );
+
+    pub static SUBSCRIPTIONS: RefCell<StableBTreeMap<u64, Subscription, VMem>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))),
+        )
+    );
+
+    // The chains the canister is connected to, keyed by chain ID.
+    pub static CHAINS: RefCell<StableBTreeMap<u64, Chain, VMem>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))),
+        )
+    );
+
+    // Hashes of recently ingested blocks, keyed by chain ID and block number. Used to detect
+    // reorgs.
+    pub static BLOCK_HASHES: RefCell<StableBTreeMap<(u64, u128), String, VMem>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))),
+        )
+    );
+
+    // Callback transactions, keyed by transaction hash.
+    pub static TRANSACTIONS: RefCell<StableBTreeMap<String, Transaction, VMem>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
+        )
+    );
+
+}
+
+/// Applies `f` to the value stored under `key` in one of the maps above and persists it.
+/// Returns false without doing anything if there is no such value.
+pub fn update_entry<K, V>(
+    map: &'static LocalKey<RefCell<StableBTreeMap<K, V, VMem>>>,
+    key: &K,
+    f: impl FnOnce(&mut V),
+) -> bool
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+{
+    map.with(|map| {
+        let mut map = map.borrow_mut();
+        match map.get(key) {
+            Some(mut value) => {
+                f(&mut value);
+                map.insert(key.clone(), value);
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Returns up to `limit` entries of one of the maps above, in the order of their keys, starting
+/// after `start_after`.
+pub fn page<K, V>(
+    map: &'static LocalKey<RefCell<StableBTreeMap<K, V, VMem>>>,
+    start_after: Option<K>,
+    limit: usize,
+) -> Vec<(K, V)>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+{
+    let start = match start_after {
+        Some(key) => RangeBound::Excluded(key),
+        None => RangeBound::Unbounded,
+    };
+    map.with(|map| {
+        map.borrow()
+            .range((start, RangeBound::Unbounded))
+            .take(limit)
+            .collect()
+    })
 }
@@ -0,0 +1,129 @@
+use crate::error::CoprocessorError;
+use crate::evm_rpc::{self, BlockTag};
+use crate::memory::{Chain, STATE};
+use crate::{chains, runtime, transactions};
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+/// How long a nonce must have been missing from the network before it is handed out again, in
+/// nanoseconds. A single provider may not have seen a transaction yet.
+const MISSING_NONCE_GRACE: u64 = 60 * 1_000_000_000;
+
+thread_local! {
+    // The nonce that the syncs of a chain found to be missing from the network, together with
+    // the time it was first reported.
+    static MISSING_NONCES: RefCell<BTreeMap<u64, (u128, u64)>> = RefCell::default();
+}
+
+/// Hands out the nonce for the next transaction sent to a chain. Gaps left by transactions that
+/// were never accepted are filled first, so that later transactions are not stuck behind them.
+pub fn reserve(chain_id: u64) -> Result<u128, String> {
+    let mut nonce = 0;
+    chains::update(chain_id, |chain| {
+        nonce = if chain.nonce_gaps().is_empty() {
+            chain.nonce += 1;
+            chain.nonce - 1
+        } else {
+            chain.nonce_gaps_mut().remove(0)
+        };
+    })?;
+    Ok(nonce)
+}
+
+/// Returns a reserved nonce whose transaction has not been accepted by the network.
+pub fn release(chain_id: u64, nonce: u128) {
+    let _ = chains::update(chain_id, |chain| {
+        if nonce + 1 == chain.nonce {
+            chain.nonce = nonce;
+        } else {
+            add_gap(chain, nonce);
+        }
+    });
+}
+
+/// Reconciles the nonces of a chain with the transaction counts of the canister's address.
+///
+/// Nonces below the `latest` count have been used by mined transactions. If the `pending` count
+/// is ahead of the local nonce, transactions have been sent that the canister does not know
+/// about, and nonces are handed out from there. If it is behind, the transaction with the
+/// `pending` nonce may be missing from the network, with everything after it stuck. That nonce
+/// is recorded as a gap and handed out again once the syncs have reported it for
+/// `MISSING_NONCE_GRACE` and no tracked transaction that may still be included uses it. Such a
+/// transaction is replaced once it is stuck instead.
+///
+/// The counts are compared with the chain as it is after they have been fetched, as nonces may
+/// have been reserved or released in the meantime.
+pub async fn sync(chain_id: u64) -> Result<(), CoprocessorError> {
+    let address = match STATE.with(|state| state.borrow().get().evm_address.clone()) {
+        Some(address) => address,
+        None => return Ok(()),
+    };
+    let chain = match chains::get(chain_id) {
+        Some(chain) => chain,
+        None => return Ok(()),
+    };
+
+    let latest = evm_rpc::get_transaction_count(&chain, address.clone(), BlockTag::Latest).await?;
+    let pending = evm_rpc::get_transaction_count(&chain, address, BlockTag::Pending)
+        .await?
+        .max(latest);
+
+    let now = runtime::time();
+    let _ = chains::update(chain_id, |chain| {
+        chain.nonce_gaps_mut().retain(|gap| *gap >= pending);
+        if pending >= chain.nonce {
+            MISSING_NONCES.with(|missing| missing.borrow_mut().remove(&chain_id));
+        }
+        if pending > chain.nonce {
+            runtime::print(format!(
+                "Chain {}: advancing nonce from {} to {}",
+                chain_id, chain.nonce, pending
+            ));
+            chain.nonce = pending;
+        } else if pending < chain.nonce {
+            let missing_since = MISSING_NONCES.with(|missing| {
+                let mut missing = missing.borrow_mut();
+                match missing.get(&chain_id) {
+                    Some((nonce, since)) if *nonce == pending => *since,
+                    _ => {
+                        missing.insert(chain_id, (pending, now));
+                        now
+                    }
+                }
+            });
+            if now.saturating_sub(missing_since) >= MISSING_NONCE_GRACE
+                && !transactions::is_pending(chain_id, pending)
+            {
+                runtime::print(format!(
+                    "Chain {}: nonce {} is missing, {} transactions are stuck behind it",
+                    chain_id,
+                    pending,
+                    chain.nonce - pending - 1
+                ));
+                add_gap(chain, pending);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Reconciles the nonces of all chains. Runs periodically, so that missing nonces are filled
+/// even if no callback is rejected because of them.
+pub async fn sync_all() {
+    for (chain_id, _) in chains::list() {
+        if let Err(error) = sync(chain_id).await {
+            runtime::print(format!(
+                "Chain {}: failed to sync nonce: {}",
+                chain_id, error
+            ));
+        }
+    }
+}
+
+fn add_gap(chain: &mut Chain, nonce: u128) {
+    let gaps = chain.nonce_gaps_mut();
+    if let Err(index) = gaps.binary_search(&nonce) {
+        gaps.insert(index, nonce);
+    }
+}
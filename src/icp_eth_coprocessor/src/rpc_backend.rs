@@ -0,0 +1,206 @@
+use ic_cdk::api::call::CallResult;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::evm_rpc::{
+    BlockTag, EvmRpcCanister, FeeHistoryArgs, GetLogsArgs, GetTransactionCountArgs,
+    MultiFeeHistoryResult, MultiGetBlockByNumberResult, MultiGetLogsResult,
+    MultiGetTransactionCountResult, MultiGetTransactionReceiptResult,
+    MultiSendRawTransactionResult, RequestCostResult, RequestResult, RpcConfig, RpcService,
+    RpcServices,
+};
+use crate::http_outcalls::HttpOutcalls;
+use crate::memory::{Chain, RpcBackend};
+use crate::runtime::BoxFuture;
+
+/// The methods of the EVM RPC canister used by the coprocessor. All Ethereum access goes
+/// through the backend returned by `backend`, which is either the EVM RPC canister or
+/// `HttpOutcalls` depending on the configuration of the chain. It can be replaced, e.g. by a
+/// `FakeEvmRpc` when testing the sync, processing and submission of jobs natively.
+pub trait EvmRpcBackend {
+    fn get_logs(
+        &self,
+        services: RpcServices,
+        config: Option<RpcConfig>,
+        args: GetLogsArgs,
+        cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiGetLogsResult,)>>;
+
+    fn eth_fee_history(
+        &self,
+        services: RpcServices,
+        config: Option<RpcConfig>,
+        args: FeeHistoryArgs,
+        cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiFeeHistoryResult,)>>;
+
+    fn eth_get_block_by_number(
+        &self,
+        services: RpcServices,
+        config: Option<RpcConfig>,
+        block: BlockTag,
+        cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiGetBlockByNumberResult,)>>;
+
+    fn eth_get_transaction_count(
+        &self,
+        services: RpcServices,
+        config: Option<RpcConfig>,
+        args: GetTransactionCountArgs,
+        cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiGetTransactionCountResult,)>>;
+
+    fn eth_get_transaction_receipt(
+        &self,
+        services: RpcServices,
+        config: Option<RpcConfig>,
+        tx_hash: String,
+        cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiGetTransactionReceiptResult,)>>;
+
+    fn request(
+        &self,
+        service: RpcService,
+        json: String,
+        max_response_bytes: u64,
+        cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(RequestResult,)>>;
+
+    fn request_cost(
+        &self,
+        service: RpcService,
+        json: String,
+        max_response_bytes: u64,
+    ) -> BoxFuture<'_, CallResult<(RequestCostResult,)>>;
+
+    fn eth_send_raw_transaction(
+        &self,
+        services: RpcServices,
+        config: Option<RpcConfig>,
+        raw_tx: String,
+        cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiSendRawTransactionResult,)>>;
+}
+
+thread_local! {
+    /// Replaces the configured backends of all chains if set.
+    static BACKEND_OVERRIDE: RefCell<Option<Rc<dyn EvmRpcBackend>>> = const { RefCell::new(None) };
+}
+
+/// The backend used to reach the providers of the chain.
+pub fn backend(chain: &Chain) -> Rc<dyn EvmRpcBackend> {
+    if let Some(backend) = BACKEND_OVERRIDE.with(|backend| backend.borrow().clone()) {
+        return backend;
+    }
+    match chain.rpc_backend() {
+        RpcBackend::EvmRpcCanister => Rc::new(EvmRpcCanister),
+        RpcBackend::HttpOutcalls => Rc::new(HttpOutcalls),
+    }
+}
+
+/// Replaces the backends of all chains by another backend.
+#[cfg(any(test, feature = "fake-evm-rpc"))]
+pub fn set_backend(backend: Rc<dyn EvmRpcBackend>) {
+    BACKEND_OVERRIDE.with(|current| *current.borrow_mut() = Some(backend));
+}
+
+impl EvmRpcBackend for EvmRpcCanister {
+    fn get_logs(
+        &self,
+        services: RpcServices,
+        config: Option<RpcConfig>,
+        args: GetLogsArgs,
+        cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiGetLogsResult,)>> {
+        Box::pin(EvmRpcCanister::get_logs(services, config, args, cycles))
+    }
+
+    fn eth_fee_history(
+        &self,
+        services: RpcServices,
+        config: Option<RpcConfig>,
+        args: FeeHistoryArgs,
+        cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiFeeHistoryResult,)>> {
+        Box::pin(EvmRpcCanister::eth_fee_history(
+            services, config, args, cycles,
+        ))
+    }
+
+    fn eth_get_block_by_number(
+        &self,
+        services: RpcServices,
+        config: Option<RpcConfig>,
+        block: BlockTag,
+        cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiGetBlockByNumberResult,)>> {
+        Box::pin(EvmRpcCanister::eth_get_block_by_number(
+            services, config, block, cycles,
+        ))
+    }
+
+    fn eth_get_transaction_count(
+        &self,
+        services: RpcServices,
+        config: Option<RpcConfig>,
+        args: GetTransactionCountArgs,
+        cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiGetTransactionCountResult,)>> {
+        Box::pin(EvmRpcCanister::eth_get_transaction_count(
+            services, config, args, cycles,
+        ))
+    }
+
+    fn eth_get_transaction_receipt(
+        &self,
+        services: RpcServices,
+        config: Option<RpcConfig>,
+        tx_hash: String,
+        cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiGetTransactionReceiptResult,)>> {
+        Box::pin(EvmRpcCanister::eth_get_transaction_receipt(
+            services, config, tx_hash, cycles,
+        ))
+    }
+
+    fn request(
+        &self,
+        service: RpcService,
+        json: String,
+        max_response_bytes: u64,
+        cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(RequestResult,)>> {
+        Box::pin(EvmRpcCanister::request(
+            service,
+            json,
+            max_response_bytes,
+            cycles,
+        ))
+    }
+
+    fn request_cost(
+        &self,
+        service: RpcService,
+        json: String,
+        max_response_bytes: u64,
+    ) -> BoxFuture<'_, CallResult<(RequestCostResult,)>> {
+        Box::pin(EvmRpcCanister::request_cost(
+            service,
+            json,
+            max_response_bytes,
+        ))
+    }
+
+    fn eth_send_raw_transaction(
+        &self,
+        services: RpcServices,
+        config: Option<RpcConfig>,
+        raw_tx: String,
+        cycles: u128,
+    ) -> BoxFuture<'_, CallResult<(MultiSendRawTransactionResult,)>> {
+        Box::pin(EvmRpcCanister::eth_send_raw_transaction(
+            services, config, raw_tx, cycles,
+        ))
+    }
+}
@@ -0,0 +1,188 @@
+use ic_cdk::api::management_canister::ecdsa::{
+    ecdsa_public_key, sign_with_ecdsa, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgument,
+    SignWithEcdsaArgument,
+};
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+
+use crate::error::CoprocessorError;
+
+/// The futures returned by the methods of the `EvmRpcBackend`, `Signer` and `JobHandler` traits.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+
+/// The current time, in nanoseconds since the Unix epoch.
+pub trait Clock {
+    fn time(&self) -> u64;
+}
+
+/// Signs transactions with the key of the canister. Like the EVM RPC backend, the clock and the
+/// signer can be replaced, e.g. by a `FakeClock` and a `FakeSigner` when running the sync,
+/// processing and submission of jobs natively.
+pub trait Signer {
+    /// The SEC1 encoded public key.
+    fn public_key(&self) -> BoxFuture<'_, Result<Vec<u8>, CoprocessorError>>;
+
+    /// Signs a 32-byte message hash. Returns the signature as `r` followed by `s`.
+    fn sign(&self, message_hash: Vec<u8>) -> BoxFuture<'_, Result<Vec<u8>, CoprocessorError>>;
+}
+
+/// Threshold ECDSA signatures of the management canister.
+struct ThresholdEcdsa;
+
+impl ThresholdEcdsa {
+    fn key_id() -> EcdsaKeyId {
+        EcdsaKeyId {
+            curve: EcdsaCurve::Secp256k1,
+            // TODO: use the correct key id
+            name: "dfx_test_key".to_string(),
+        }
+    }
+}
+
+impl Signer for ThresholdEcdsa {
+    fn public_key(&self) -> BoxFuture<'_, Result<Vec<u8>, CoprocessorError>> {
+        Box::pin(async move {
+            let (key,) = ecdsa_public_key(EcdsaPublicKeyArgument {
+                canister_id: None,
+                derivation_path: [].to_vec(),
+                key_id: Self::key_id(),
+            })
+            .await?;
+            Ok(key.public_key)
+        })
+    }
+
+    fn sign(&self, message_hash: Vec<u8>) -> BoxFuture<'_, Result<Vec<u8>, CoprocessorError>> {
+        Box::pin(async move {
+            let (signature,) = sign_with_ecdsa(SignWithEcdsaArgument {
+                message_hash,
+                derivation_path: [].to_vec(),
+                key_id: Self::key_id(),
+            })
+            .await?;
+            Ok(signature.signature)
+        })
+    }
+}
+
+thread_local! {
+    /// Replaces the system time if set.
+    static CLOCK_OVERRIDE: RefCell<Option<Rc<dyn Clock>>> = const { RefCell::new(None) };
+
+    /// Replaces threshold ECDSA if set.
+    static SIGNER_OVERRIDE: RefCell<Option<Rc<dyn Signer>>> = const { RefCell::new(None) };
+}
+
+/// The current time, in nanoseconds since the Unix epoch.
+pub fn time() -> u64 {
+    match CLOCK_OVERRIDE.with(|clock| clock.borrow().clone()) {
+        Some(clock) => clock.time(),
+        None => ic_cdk::api::time(),
+    }
+}
+
+/// The signer of the transactions of the canister.
+pub fn signer() -> Rc<dyn Signer> {
+    SIGNER_OVERRIDE
+        .with(|signer| signer.borrow().clone())
+        .unwrap_or_else(|| Rc::new(ThresholdEcdsa))
+}
+
+/// Prints to the canister log. Outside of a canister, where the system API is not available,
+/// the message goes to the standard error.
+pub fn print<S: AsRef<str>>(message: S) {
+    #[cfg(target_arch = "wasm32")]
+    ic_cdk::print(message);
+    #[cfg(not(target_arch = "wasm32"))]
+    eprintln!("{}", message.as_ref());
+}
+
+#[cfg(any(test, feature = "fake-evm-rpc"))]
+pub use fakes::{FakeClock, FakeSigner};
+
+/// Replaces the system time by another clock.
+#[cfg(any(test, feature = "fake-evm-rpc"))]
+pub fn set_clock(clock: Rc<dyn Clock>) {
+    CLOCK_OVERRIDE.with(|current| *current.borrow_mut() = Some(clock));
+}
+
+/// Replaces threshold ECDSA by another signer.
+#[cfg(any(test, feature = "fake-evm-rpc"))]
+pub fn set_signer(signer: Rc<dyn Signer>) {
+    SIGNER_OVERRIDE.with(|current| *current.borrow_mut() = Some(signer));
+}
+
+#[cfg(any(test, feature = "fake-evm-rpc"))]
+mod fakes {
+    use ethers_core::k256::ecdsa::signature::hazmat::PrehashSigner;
+    use ethers_core::k256::ecdsa::{Signature, SigningKey};
+
+    use std::cell::Cell;
+
+    use super::{BoxFuture, Clock, Signer};
+    use crate::error::CoprocessorError;
+
+    /// A clock that only moves when the test advances it.
+    #[derive(Default)]
+    pub struct FakeClock {
+        now: Cell<u64>,
+    }
+
+    impl FakeClock {
+        pub fn new(now: u64) -> Self {
+            FakeClock {
+                now: Cell::new(now),
+            }
+        }
+
+        pub fn advance_secs(&self, secs: u64) {
+            self.now.set(self.now.get() + secs * 1_000_000_000);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn time(&self) -> u64 {
+            self.now.get()
+        }
+    }
+
+    /// Signs with a local secp256k1 key instead of threshold ECDSA.
+    pub struct FakeSigner {
+        key: SigningKey,
+    }
+
+    impl FakeSigner {
+        /// A signer with a fixed key, so that the address of the canister is the same in all
+        /// tests.
+        pub fn new() -> Self {
+            FakeSigner {
+                key: SigningKey::from_slice(&[1; 32]).expect("The key is a valid scalar"),
+            }
+        }
+    }
+
+    impl Default for FakeSigner {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Signer for FakeSigner {
+        fn public_key(&self) -> BoxFuture<'_, Result<Vec<u8>, CoprocessorError>> {
+            let key = self.key.verifying_key().to_encoded_point(true);
+            Box::pin(async move { Ok(key.as_bytes().to_vec()) })
+        }
+
+        fn sign(&self, message_hash: Vec<u8>) -> BoxFuture<'_, Result<Vec<u8>, CoprocessorError>> {
+            let signature: Result<Signature, _> = self.key.sign_prehash(&message_hash);
+            Box::pin(async move {
+                signature
+                    .map(|signature| signature.to_bytes().to_vec())
+                    .map_err(|e| CoprocessorError::SigningError(e.to_string()))
+            })
+        }
+    }
+}
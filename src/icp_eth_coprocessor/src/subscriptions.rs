@@ -0,0 +1,273 @@
+use candid::{CandidType, Deserialize};
+
+use ethers_core::abi::{EventExt, HumanReadableParser};
+use ethers_core::types::Address;
+
+use std::str::FromStr;
+
+use crate::error::CoprocessorError;
+use crate::evm_rpc::{self, BlockTag};
+use crate::memory::{
+    update_entry, Subscription, CONFIG, ETH_SEPOLIA_CHAIN_ID, NEXT_SUBSCRIPTION_ID, STATE,
+    SUBSCRIPTIONS,
+};
+use crate::{chains, events, handlers, runtime};
+
+/// The event subscribed to if none is given.
+pub const DEFAULT_EVENT: &str = handlers::NEW_JOB_EVENT;
+
+/// The function receiving the results if none is given.
+pub const DEFAULT_CALLBACK: &str = "function callback(string)";
+
+/// The gas limit of callbacks if none is given.
+pub const DEFAULT_MAX_GAS: u128 = 500_000;
+
+#[derive(CandidType, Deserialize)]
+pub struct SubscriptionArgs {
+    /// Defaults to Sepolia.
+    pub chain_id: Option<u64>,
+    pub contract: String,
+    /// Defaults to `DEFAULT_EVENT`.
+    pub event: Option<String>,
+    pub topic_filters: Option<Vec<Vec<String>>>,
+    /// Defaults to the latest block.
+    pub start_block: Option<u128>,
+    /// Defaults to `DEFAULT_CALLBACK`.
+    pub callback: Option<String>,
+    pub handler: Option<String>,
+    /// Defaults to `DEFAULT_MAX_GAS`.
+    pub max_gas: Option<u128>,
+}
+
+/// Validates and stores a new subscription. Returns the ID of the subscription.
+pub async fn add(args: SubscriptionArgs) -> Result<u64, CoprocessorError> {
+    let invalid = CoprocessorError::InvalidArgument;
+
+    let chain_id = args.chain_id.unwrap_or(ETH_SEPOLIA_CHAIN_ID);
+    let chain = chains::get(chain_id).ok_or(invalid(format!("Chain {} not found", chain_id)))?;
+
+    Address::from_str(&args.contract)
+        .map_err(|e| invalid(format!("Invalid contract address {}: {}", args.contract, e)))?;
+
+    let event_abi = args.event.unwrap_or(DEFAULT_EVENT.to_string());
+    let event = events::parse_event(&event_abi).map_err(invalid)?;
+    let signature = event.abi_signature();
+
+    let callback = args.callback.unwrap_or(DEFAULT_CALLBACK.to_string());
+    HumanReadableParser::parse_function(&callback)
+        .map_err(|e| invalid(format!("Invalid callback ABI {}: {}", callback, e)))?;
+
+    let topic_filters = args.topic_filters.unwrap_or_default();
+    validate_topic_filters(&topic_filters).map_err(invalid)?;
+
+    let mut subscription = Subscription {
+        chain_id,
+        contract: args.contract,
+        event: event_abi,
+        topic_filters,
+        start_block: 0,
+        cursor: 0,
+        callback,
+        handler: args.handler,
+        max_gas: args.max_gas.unwrap_or(DEFAULT_MAX_GAS),
+        paused: false,
+    };
+
+    if handlers::handler_for(&subscription, &signature).is_none() {
+        return Err(invalid(format!(
+            "No handler registered for {} of {}",
+            signature, subscription.contract
+        )));
+    }
+
+    check_not_duplicate(&subscription, &signature).map_err(invalid)?;
+
+    let start_block = match args.start_block {
+        Some(start_block) => start_block,
+        None => {
+            evm_rpc::get_block_by_number(&chain, BlockTag::Latest)
+                .await?
+                .number
+        }
+    };
+    // The chain is read again as jobs may have been pruned while the latest block was requested
+    let pruned_block = chains::get(chain_id).and_then(|chain| chain.pruned_block);
+    if let Some(pruned_block) = pruned_block.filter(|pruned| start_block <= *pruned) {
+        return Err(invalid(format!(
+            "Start block {} is not after block {}, whose processed logs have been pruned",
+            start_block, pruned_block
+        )));
+    }
+    subscription.start_block = start_block;
+    subscription.cursor = start_block.saturating_sub(1);
+
+    // The same subscription may have been added while the latest block was requested
+    check_not_duplicate(&subscription, &signature).map_err(invalid)?;
+    Ok(insert(subscription))
+}
+
+/// Turns the contract configured with `set_contract` before subscriptions were introduced into
+/// a subscription to its `NewJob` events on Sepolia, which continues after the last synced
+/// block. Called after every upgrade, does nothing once the contract has been migrated.
+pub fn migrate_legacy_contract() {
+    let contract = match CONFIG.with(|config| config.borrow().get().evm_contract.clone()) {
+        Some(contract) => contract,
+        None => return,
+    };
+    let synced_block = STATE
+        .with(|state| state.borrow().get().evm_block_height)
+        .unwrap_or(0);
+
+    let subscription = Subscription {
+        chain_id: ETH_SEPOLIA_CHAIN_ID,
+        contract,
+        event: DEFAULT_EVENT.to_string(),
+        topic_filters: Vec::new(),
+        start_block: synced_block + 1,
+        cursor: synced_block,
+        callback: DEFAULT_CALLBACK.to_string(),
+        handler: None,
+        max_gas: DEFAULT_MAX_GAS,
+        paused: false,
+    };
+    let signature = events::parse_event(DEFAULT_EVENT)
+        .expect("The default event is valid")
+        .abi_signature();
+    match check_not_duplicate(&subscription, &signature) {
+        Ok(()) => {
+            let id = insert(subscription);
+            runtime::print(format!("Contract migrated to subscription {}", id));
+        }
+        Err(error) => runtime::print(format!("Contract not migrated: {}", error)),
+    }
+
+    CONFIG.with(|config| {
+        let mut c = config.borrow().get().clone();
+        c.evm_contract = None;
+        config.borrow_mut().set(c).expect("Failed to set config");
+    });
+    STATE.with(|state| {
+        let mut s = state.borrow().get().clone();
+        s.evm_block_height = None;
+        state.borrow_mut().set(s).expect("Failed to set state");
+    });
+}
+
+pub fn remove(id: u64) -> Result<(), String> {
+    SUBSCRIPTIONS
+        .with(|subscriptions| subscriptions.borrow_mut().remove(&id))
+        .map(|_| ())
+        .ok_or(format!("Subscription {} not found", id))
+}
+
+/// Pausing a subscription stops the ingestion of its logs. Jobs that have already been queued
+/// are still processed.
+pub fn set_paused(id: u64, paused: bool) -> Result<(), String> {
+    update(id, |subscription| subscription.paused = paused)
+}
+
+pub fn get(id: u64) -> Option<Subscription> {
+    SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow().get(&id))
+}
+
+pub fn list() -> Vec<(u64, Subscription)> {
+    SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow().iter().collect())
+}
+
+/// Returns the subscriptions that are not paused.
+pub fn active() -> Vec<(u64, Subscription)> {
+    SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions
+            .borrow()
+            .iter()
+            .filter(|(_, subscription)| !subscription.paused)
+            .collect()
+    })
+}
+
+pub fn set_cursor(id: u64, cursor: u128) {
+    let _ = update(id, |subscription| subscription.cursor = cursor);
+}
+
+/// Moves the cursor of all subscriptions of a chain that synced past `block` back to `block`.
+pub fn rewind(chain_id: u64, block: u128) {
+    SUBSCRIPTIONS.with(|subscriptions| {
+        let mut subscriptions = subscriptions.borrow_mut();
+        let ahead: Vec<(u64, Subscription)> = subscriptions
+            .iter()
+            .filter(|(_, subscription)| {
+                subscription.chain_id == chain_id && subscription.cursor > block
+            })
+            .collect();
+        for (id, mut subscription) in ahead {
+            subscription.cursor = block;
+            subscriptions.insert(id, subscription);
+        }
+    });
+}
+
+/// Logs are identified by their position, so two subscriptions to the same event of the same
+/// contract would compete for the same jobs.
+fn check_not_duplicate(subscription: &Subscription, signature: &str) -> Result<(), String> {
+    let duplicate = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().iter().any(|(_, other)| {
+            other.chain_id == subscription.chain_id
+                && other.contract.eq_ignore_ascii_case(&subscription.contract)
+                && events::parse_event(&other.event)
+                    .map(|other_event| other_event.abi_signature() == signature)
+                    .unwrap_or(false)
+        })
+    });
+    if duplicate {
+        return Err(format!(
+            "Already subscribed to {} of {} on chain {}",
+            signature, subscription.contract, subscription.chain_id
+        ));
+    }
+    Ok(())
+}
+
+/// Stores a new subscription under the next unused ID and returns the ID.
+fn insert(subscription: Subscription) -> u64 {
+    // Subscriptions stored before the counter was introduced are skipped as well
+    let next_unused = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions
+            .borrow()
+            .last_key_value()
+            .map(|(id, _)| id + 1)
+    });
+    let id = NEXT_SUBSCRIPTION_ID
+        .with(|next| *next.borrow().get())
+        .max(next_unused.unwrap_or(0));
+    NEXT_SUBSCRIPTION_ID.with(|next| {
+        next.borrow_mut()
+            .set(id + 1)
+            .expect("Failed to set the next subscription ID")
+    });
+    SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow_mut().insert(id, subscription));
+    id
+}
+
+/// Checks that topic filters address at most the three indexed parameters of an event and
+/// only contain 32-byte hex values.
+fn validate_topic_filters(topic_filters: &[Vec<String>]) -> Result<(), String> {
+    if topic_filters.len() > 3 {
+        return Err("An event has at most 3 indexed parameters".to_string());
+    }
+    for topic in topic_filters.iter().flatten() {
+        let valid = topic.len() == 66
+            && topic.starts_with("0x")
+            && topic[2..].chars().all(|c| c.is_ascii_hexdigit());
+        if !valid {
+            return Err(format!("Invalid topic: {}", topic));
+        }
+    }
+    Ok(())
+}
+
+fn update(id: u64, f: impl FnOnce(&mut Subscription)) -> Result<(), String> {
+    if !update_entry(&SUBSCRIPTIONS, &id, f) {
+        return Err(format!("Subscription {} not found", id));
+    }
+    Ok(())
+}
@@ -0,0 +1,402 @@
+use crate::error::CoprocessorError;
+use crate::events;
+use crate::evm_rpc::{self, BlockTag, HttpOutcallError, JsonRpcError, LogEntry, ProviderError};
+use crate::guard::RunGuard;
+use crate::memory::{
+    Chain, ConfirmationPolicy, EventError, LogId, Subscription, BLOCK_HASHES, EVENT_ERRORS,
+};
+use crate::{chains, jobs, runtime, subscriptions};
+
+use ethers_core::abi::Event;
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+/// Number of recently ingested block hashes kept around for reorg detection.
+const MAX_TRACKED_BLOCKS: u64 = 128;
+
+/// Maximum number of `eth_getLogs` windows requested in a single sync run.
+const MAX_WINDOWS_PER_SYNC: usize = 10;
+
+thread_local! {
+    // Current size of the block window used for `eth_getLogs`, keyed by chain ID and
+    // subscription ID. Shrinks when responses get too large and grows back after successful
+    // requests. Busy contracts thereby do not narrow the windows of other subscriptions.
+    static LOG_WINDOWS: RefCell<BTreeMap<(u64, u64), u128>> = RefCell::default();
+
+    static SYNC_IN_PROGRESS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// A block that is considered confirmed according to the configured `ConfirmationPolicy`.
+struct ConfirmedHead {
+    number: u128,
+    hash: String,
+}
+
+pub async fn sync_logs() {
+    // Prevents overlapping sync runs when a run takes longer than the timer interval
+    let _guard = match RunGuard::new(&SYNC_IN_PROGRESS) {
+        Some(guard) => guard,
+        None => return,
+    };
+
+    // Only chains with active subscriptions are synced
+    let mut chain_ids: Vec<u64> = subscriptions::active()
+        .iter()
+        .map(|(_, subscription)| subscription.chain_id)
+        .collect();
+    chain_ids.sort();
+    chain_ids.dedup();
+
+    for chain_id in chain_ids {
+        match chains::get(chain_id) {
+            Some(chain) => sync_chain(chain_id, &chain).await,
+            None => runtime::print(format!("Chain {} not found", chain_id)),
+        }
+    }
+}
+
+async fn sync_chain(chain_id: u64, chain: &Chain) {
+    // Logs are only ingested once it is known that the tracked blocks are still canonical
+    if let Err(error) = check_for_reorg(chain_id, chain).await {
+        runtime::print(format!(
+            "Chain {}: failed to check for reorgs: {}",
+            chain_id, error
+        ));
+        chains::set_sync_error(chain_id, error);
+        return;
+    }
+
+    let head = match confirmed_head(chain).await {
+        Ok(head) => head,
+        Err(error) => {
+            runtime::print(format!(
+                "Chain {}: failed to get the confirmed head: {}",
+                chain_id, error
+            ));
+            chains::set_sync_error(chain_id, error);
+            return;
+        }
+    };
+
+    // Subscriptions are read again as their cursors may have been rewound because of a reorg.
+    // A failing subscription does not hold up the others, its error is reported for the chain.
+    let mut subscription_error = None;
+    for (id, subscription) in subscriptions::active() {
+        if subscription.chain_id == chain_id {
+            if let Err(error) = sync_subscription(chain, id, subscription, head.number).await {
+                subscription_error = Some(error);
+            }
+        }
+    }
+
+    BLOCK_HASHES.with(|hashes| {
+        hashes
+            .borrow_mut()
+            .insert((chain_id, head.number), head.hash)
+    });
+    prune_block_hashes(chain_id);
+    chains::set_synced_block(chain_id, head.number);
+    if let Some(error) = subscription_error {
+        chains::set_sync_error(chain_id, error);
+    }
+}
+
+/// Ingests the logs of a subscription up to the confirmed head, in windows of at most
+/// `max_block_range` blocks. Returns the error that stopped the subscription short of the head.
+async fn sync_subscription(
+    chain: &Chain,
+    id: u64,
+    subscription: Subscription,
+    head: u128,
+) -> Result<(), CoprocessorError> {
+    let mut cursor = subscription.cursor;
+    if head <= cursor {
+        return Ok(());
+    }
+
+    let event = events::parse_event(&subscription.event).map_err(|error| {
+        runtime::print(format!("Subscription {}: {}", id, error));
+        CoprocessorError::InvalidRequest(format!("Subscription {}: {}", id, error))
+    })?;
+    let topics = log_topics(&subscription, &event);
+
+    let max_window = chain.max_block_range() as u128;
+    let mut window = LOG_WINDOWS
+        .with(|windows| windows.borrow().get(&(subscription.chain_id, id)).copied())
+        .unwrap_or(max_window)
+        .clamp(1, max_window);
+
+    let mut result = Ok(());
+    for _ in 0..MAX_WINDOWS_PER_SYNC {
+        if cursor >= head {
+            break;
+        }
+        let to_block = (cursor + window).min(head);
+
+        let logs = match evm_rpc::get_logs(
+            chain,
+            [subscription.contract.clone()].to_vec(),
+            Some(topics.clone()),
+            cursor + 1,
+            BlockTag::Number(to_block),
+        )
+        .await
+        {
+            Ok(logs) => logs,
+            Err(e) if should_shrink_window(&e) && window > 1 => {
+                window = (window / 2).max(1);
+                runtime::print(format!(
+                    "Shrinking log window to {} blocks after error: {}",
+                    window, e
+                ));
+                continue;
+            }
+            Err(e) => {
+                runtime::print(format!(
+                    "Failed to get logs for blocks {} to {}: {}",
+                    cursor + 1,
+                    to_block,
+                    e
+                ));
+                result = Err(e);
+                break;
+            }
+        };
+
+        for log in logs.iter() {
+            // The log was part of a block that is no longer canonical
+            if log.removed {
+                match log_id(log) {
+                    Some(log_id) => jobs::cancel(&log_id),
+                    None => runtime::print(format!("Removed log without position: {:?}", log)),
+                }
+                continue;
+            }
+
+            if let (Some(number), Some(hash)) = (log.blockNumber, log.blockHash.clone()) {
+                BLOCK_HASHES.with(|hashes| {
+                    hashes
+                        .borrow_mut()
+                        .insert((subscription.chain_id, number), hash)
+                });
+            }
+
+            let decoded = match events::decode_log(&event, log) {
+                Ok(decoded) => decoded,
+                Err(error) => {
+                    record_event_error(log, error);
+                    continue;
+                }
+            };
+            match (log_id(log), log.blockNumber) {
+                (Some(log_id), Some(block_number)) => {
+                    jobs::enqueue(
+                        log_id,
+                        id,
+                        subscription.chain_id,
+                        log.address.clone(),
+                        block_number,
+                        decoded,
+                    );
+                }
+                _ => runtime::print(format!("Skipping log without position: {:?}", log)),
+            }
+        }
+
+        // Persist the cursor after every window so that progress survives a trap
+        cursor = to_block;
+        subscriptions::set_cursor(id, cursor);
+
+        // Slowly grow the window again after it had to be shrunk
+        window = (window * 2).min(max_window);
+    }
+
+    LOG_WINDOWS.with(|windows| {
+        windows
+            .borrow_mut()
+            .insert((subscription.chain_id, id), window)
+    });
+    result
+}
+
+/// Builds the topic filter for `eth_getLogs`: topic 0 selects the event of the subscription,
+/// followed by the filters for the indexed event parameters.
+fn log_topics(subscription: &Subscription, event: &Event) -> Vec<Vec<String>> {
+    let mut topics = vec![vec![events::topic0(event)]];
+    topics.extend(subscription.topic_filters.clone());
+    topics
+}
+
+/// Returns true if the error indicates that the requested block range yields a response that is
+/// too large, in which case the request is retried with a smaller range.
+fn should_shrink_window(error: &CoprocessorError) -> bool {
+    match error {
+        CoprocessorError::ProviderError(ProviderError::TooFewCycles { .. }) => true,
+        CoprocessorError::HttpOutcallError(HttpOutcallError::IcError { message, .. }) => {
+            message.contains("size limit") || message.contains("max_response_bytes")
+        }
+        // Providers signal too many results or a too wide range with "limit exceeded"
+        CoprocessorError::JsonRpcError(JsonRpcError { code, message }) => {
+            *code == -32005 || message.contains("range") || message.contains("size exceeded")
+        }
+        _ => false,
+    }
+}
+
+/// Resolves the newest block of a chain that satisfies its confirmation policy.
+async fn confirmed_head(chain: &Chain) -> Result<ConfirmedHead, CoprocessorError> {
+    let block = match chain.confirmation_policy() {
+        ConfirmationPolicy::Depth(depth) => {
+            let latest = evm_rpc::get_block_by_number(chain, BlockTag::Latest).await?;
+            let number = latest.number.saturating_sub(depth as u128);
+            evm_rpc::get_block_by_number(chain, BlockTag::Number(number)).await?
+        }
+        ConfirmationPolicy::Safe => evm_rpc::get_block_by_number(chain, BlockTag::Safe).await?,
+        ConfirmationPolicy::Finalized => {
+            evm_rpc::get_block_by_number(chain, BlockTag::Finalized).await?
+        }
+    };
+
+    Ok(ConfirmedHead {
+        number: block.number,
+        hash: block.hash,
+    })
+}
+
+/// Compares the stored hashes of recently ingested blocks of a chain with its canonical chain.
+///
+/// If the most recent tracked block has been replaced, the newest tracked block that is still
+/// canonical is searched for. As every block after a replaced block has been replaced as well,
+/// a binary search over the tracked blocks needs at most 8 requests for 128 tracked blocks. The
+/// cursors of all subscriptions of the chain are rewound to that block so that the logs of all
+/// orphaned blocks are fetched (and their jobs processed) again.
+async fn check_for_reorg(chain_id: u64, chain: &Chain) -> Result<(), CoprocessorError> {
+    let tracked: Vec<(u128, String)> = BLOCK_HASHES.with(|hashes| {
+        hashes
+            .borrow()
+            .range((chain_id, 0)..=(chain_id, u128::MAX))
+            .map(|((_, number), hash)| (number, hash))
+            .collect()
+    });
+
+    let is_canonical = |index: usize| {
+        let (number, hash) = tracked[index].clone();
+        async move {
+            let block = evm_rpc::get_block_by_number(chain, BlockTag::Number(number)).await?;
+            Ok::<_, CoprocessorError>(block.hash == hash)
+        }
+    };
+
+    let newest = match tracked.len().checked_sub(1) {
+        Some(newest) => newest,
+        None => return Ok(()),
+    };
+    if is_canonical(newest).await? {
+        return Ok(());
+    }
+
+    // The tracked blocks in `..low` are canonical, the ones in `high..` have been replaced
+    let (mut low, mut high) = (0, newest);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if is_canonical(mid).await? {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    let orphaned: Vec<u128> = tracked[high..].iter().map(|(number, _)| *number).collect();
+
+    let rewind_to = match high.checked_sub(1) {
+        Some(common_ancestor) => tracked[common_ancestor].0,
+        None => {
+            runtime::print(format!(
+                "Reorg is deeper than the {} tracked blocks",
+                MAX_TRACKED_BLOCKS
+            ));
+            orphaned[0].saturating_sub(1)
+        }
+    };
+
+    let rolled_back = jobs::roll_back(chain_id, rewind_to + 1);
+    runtime::print(format!(
+        "Reorg detected on chain {}: blocks {:?} were replaced, re-queuing {} jobs from block {}",
+        chain_id,
+        orphaned,
+        rolled_back.len(),
+        rewind_to + 1
+    ));
+
+    BLOCK_HASHES.with(|hashes| {
+        let mut hashes = hashes.borrow_mut();
+        let stale: Vec<(u64, u128)> = hashes
+            .range((chain_id, rewind_to + 1)..=(chain_id, u128::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in stale {
+            hashes.remove(&key);
+        }
+    });
+    subscriptions::rewind(chain_id, rewind_to);
+    chains::rewind(chain_id, rewind_to);
+    Ok(())
+}
+
+/// The oldest block of a chain whose hash is tracked. Logs of older blocks are not synced again,
+/// even after a reorg.
+pub fn lowest_tracked_block(chain_id: u64) -> Option<u128> {
+    BLOCK_HASHES.with(|hashes| {
+        hashes
+            .borrow()
+            .range((chain_id, 0)..=(chain_id, u128::MAX))
+            .next()
+            .map(|((_, number), _)| number)
+    })
+}
+
+fn prune_block_hashes(chain_id: u64) {
+    BLOCK_HASHES.with(|hashes| {
+        let mut hashes = hashes.borrow_mut();
+        let tracked: Vec<(u64, u128)> = hashes
+            .range((chain_id, 0)..=(chain_id, u128::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        let excess = tracked.len().saturating_sub(MAX_TRACKED_BLOCKS as usize);
+        for key in tracked.into_iter().take(excess) {
+            hashes.remove(&key);
+        }
+    });
+}
+
+/// Identifies a log by its transaction hash and log index. Only pending logs lack those.
+fn log_id(log: &LogEntry) -> Option<LogId> {
+    match (log.transactionHash.clone(), log.logIndex) {
+        (Some(transaction_hash), Some(log_index)) => Some(LogId {
+            transaction_hash,
+            log_index,
+        }),
+        _ => None,
+    }
+}
+
+/// Records a log that could not be decoded, so that it shows up as an error instead of
+/// silently being turned into a job.
+fn record_event_error(log: &LogEntry, error: String) {
+    runtime::print(format!("Failed to decode log {:?}: {}", log, error));
+
+    let log_id = match log_id(log) {
+        Some(log_id) => log_id,
+        None => return,
+    };
+    EVENT_ERRORS.with(|errors| {
+        errors.borrow_mut().insert(
+            log_id,
+            EventError {
+                block_number: log.blockNumber,
+                error,
+                recorded_at: Some(runtime::time()),
+            },
+        )
+    });
+}
@@ -0,0 +1,628 @@
+//! Runs the sync, processing and submission of jobs natively against a `FakeEvmRpc`.
+
+use ethers_core::abi::Token;
+use ethers_core::types::transaction::eip2718::TypedTransaction;
+use ethers_core::utils::rlp::Rlp;
+
+use std::future::Future;
+use std::pin::pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use crate::error::CoprocessorError;
+use crate::events::{AbiValue, DecodedEvent};
+use crate::evm_rpc::{
+    self, FeeHistory, HttpOutcallError, LogEntry, RpcError, SendRawTransactionStatus,
+    TransactionReceipt,
+};
+use crate::fake_evm_rpc::FakeEvmRpc;
+use crate::handlers::{JobContext, JobHandler, JobResult};
+use crate::memory::{
+    ConfirmationPolicy, JobStatus, LogId, TransactionStatus, ETH_SEPOLIA_CHAIN_ID, JOBS, STATE,
+    TRANSACTIONS,
+};
+use crate::runtime::{self, BoxFuture, FakeClock, FakeSigner};
+use crate::subscriptions::SubscriptionArgs;
+use crate::{
+    chains, events, evm_signer, handlers, jobs, nonce, rpc_backend, subscriptions, sync,
+    transactions,
+};
+
+const CONTRACT: &str = "0x5FbDB2315678afecb367f032d93F642f64180aa3";
+
+/// The hash of a block that replaced the block with the same number in a reorg. The fake derives
+/// the hashes of all other blocks from their number.
+fn replaced_hash(number: u128) -> String {
+    format!("0x{:064x}", (1u128 << 64) + number)
+}
+
+/// Runs a future of the pipeline to completion. The fakes answer immediately, so the future
+/// never has to wait.
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    match future
+        .as_mut()
+        .poll(&mut Context::from_waker(Waker::noop()))
+    {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("The future is waiting for something other than the fakes"),
+    }
+}
+
+/// Sets up the canister with a subscription to the `NewJob` events of `CONTRACT` on Sepolia,
+/// starting at block 100. Every test runs on its own thread and thereby gets its own state.
+fn setup() -> (Rc<FakeEvmRpc>, Rc<FakeClock>) {
+    let fake = Rc::new(FakeEvmRpc::new());
+    rpc_backend::set_backend(fake.clone());
+    let clock = Rc::new(FakeClock::new(1_700_000_000_000_000_000));
+    runtime::set_clock(clock.clone());
+    runtime::set_signer(Rc::new(FakeSigner::new()));
+
+    handlers::register_handlers();
+    chains::init_default_chains();
+
+    let pubkey = block_on(evm_signer::get_public_key()).unwrap();
+    let evm_address = evm_signer::pubkey_bytes_to_address(&pubkey).unwrap();
+    STATE.with(|state| {
+        let mut s = state.borrow().get().clone();
+        s.ecdsa_pub_key = Some(pubkey);
+        s.evm_address = Some(evm_address.clone());
+        state.borrow_mut().set(s).unwrap();
+    });
+
+    block_on(subscriptions::add(SubscriptionArgs {
+        chain_id: None,
+        contract: CONTRACT.to_string(),
+        event: None,
+        topic_filters: None,
+        start_block: Some(100),
+        callback: None,
+        handler: None,
+        max_gas: None,
+    }))
+    .unwrap();
+
+    fake.set_response("eth_estimateGas", serde_json::json!("0x7530"));
+    // The contract accepts callbacks from the canister
+    fake.set_response("eth_call", coprocessor_result(&evm_address));
+    fake.set_fee_history(FeeHistory {
+        reward: vec![vec![1_000_000_000]],
+        gasUsedRatio: vec![0.5],
+        oldestBlock: 100,
+        baseFeePerGas: vec![10_000_000_000, 10_000_000_000],
+    });
+    (fake, clock)
+}
+
+/// The return data of `coprocessor()`, the address the contract accepts callbacks from.
+fn coprocessor_result(address: &str) -> serde_json::Value {
+    serde_json::json!(format!(
+        "0x{:0>64}",
+        address.trim_start_matches("0x").to_lowercase()
+    ))
+}
+
+/// The `NewJob(job_id)` log of `CONTRACT`. Every job is requested by its own transaction.
+fn new_job_log(block_number: u128, block_hash: String, job_id: u64) -> LogEntry {
+    let event = events::parse_event(handlers::NEW_JOB_EVENT).unwrap();
+    LogEntry {
+        transactionHash: Some(format!("0x{:064x}", job_id)),
+        blockNumber: Some(block_number),
+        data: format!("0x{:064x}", job_id),
+        blockHash: Some(block_hash),
+        transactionIndex: Some(0),
+        topics: vec![events::topic0(&event)],
+        address: CONTRACT.to_string(),
+        logIndex: Some(0),
+        removed: false,
+    }
+}
+
+/// Emits `NewJob(job_id)` in a block of the current chain. Returns the ID of the log.
+fn emit_new_job(fake: &FakeEvmRpc, block_number: u128, job_id: u64) -> LogId {
+    fake.add_log(new_job_log(
+        block_number,
+        format!("0x{:064x}", block_number),
+        job_id,
+    ));
+    LogId {
+        transaction_hash: format!("0x{:064x}", job_id),
+        log_index: 0,
+    }
+}
+
+fn receipt(transaction_hash: &str, block_number: u128) -> TransactionReceipt {
+    TransactionReceipt {
+        to: CONTRACT.to_string(),
+        status: 1,
+        transactionHash: transaction_hash.to_string(),
+        blockNumber: block_number,
+        from: evm_address(),
+        logs: vec![],
+        blockHash: format!("0x{:064x}", block_number),
+        r#type: "0x2".to_string(),
+        transactionIndex: 0,
+        effectiveGasPrice: 11_000_000_000,
+        logsBloom: "0x".to_string(),
+        contractAddress: None,
+        gasUsed: 25_000,
+    }
+}
+
+fn evm_address() -> String {
+    STATE.with(|state| state.borrow().get().evm_address.clone().unwrap())
+}
+
+/// Decodes a raw transaction sent to the fake. Fails unless it has been signed by the canister.
+fn decode_sent(raw_tx: &str) -> TypedTransaction {
+    let raw = hex::decode(raw_tx.trim_start_matches("0x")).unwrap();
+    let (tx, signature) = TypedTransaction::decode_signed(&Rlp::new(&raw)).unwrap();
+    let sender = signature.recover(tx.sighash()).unwrap();
+    assert_eq!(
+        ethers_core::utils::to_checksum(&sender, None),
+        evm_address()
+    );
+    tx
+}
+
+fn job_status(log_id: &LogId) -> Option<JobStatus> {
+    JOBS.with(|jobs| jobs.borrow().get(log_id))
+        .map(|job| job.status)
+}
+
+#[test]
+fn job_is_synced_processed_submitted_and_confirmed() {
+    let (fake, _) = setup();
+    // Logs are ingested 12 blocks behind the head on Sepolia
+    fake.set_head(120);
+    let log_id = emit_new_job(&fake, 105, 1);
+
+    block_on(sync::sync_logs());
+    assert_eq!(job_status(&log_id), Some(JobStatus::Discovered));
+    assert_eq!(subscriptions::get(0).unwrap().cursor, 108);
+
+    block_on(jobs::process_queue());
+    assert_eq!(job_status(&log_id), Some(JobStatus::Submitted));
+    assert!(jobs::is_processed(&log_id));
+
+    // The callback is signed by the canister and carries the result of the handler
+    let sent = fake.sent_transactions();
+    assert_eq!(sent.len(), 1);
+    let tx = decode_sent(&sent[0]);
+    assert_eq!(tx.nonce(), Some(&0.into()));
+    let callback = events::parse_function(subscriptions::DEFAULT_CALLBACK).unwrap();
+    let result = callback.decode_input(&tx.data().unwrap()[4..]).unwrap();
+    assert_eq!(result[0].clone().into_string().unwrap(), "42");
+
+    let hash = evm_signer::transaction_hash(&sent[0]);
+    assert_eq!(transactions::hashes_for(&log_id), vec![hash.clone()]);
+
+    // No receipt yet
+    block_on(transactions::poll_receipts());
+    assert_eq!(job_status(&log_id), Some(JobStatus::Submitted));
+
+    fake.set_receipt(receipt(&hash, 121));
+    block_on(transactions::poll_receipts());
+    assert_eq!(job_status(&log_id), Some(JobStatus::Confirmed));
+    let transaction = TRANSACTIONS.with(|transactions| transactions.borrow().get(&hash).unwrap());
+    assert_eq!(transaction.status, TransactionStatus::Confirmed);
+    assert_eq!(transaction.block_number, Some(121));
+    assert_eq!(transaction.gas_used, Some(25_000));
+}
+
+#[test]
+fn subscriptions_cannot_start_at_pruned_blocks() {
+    let (fake, clock) = setup();
+    fake.set_head(120);
+    let log_id = emit_new_job(&fake, 105, 1);
+    block_on(sync::sync_logs());
+    block_on(jobs::process_queue());
+    let hash = transactions::hashes_for(&log_id)[0].clone();
+    fake.set_receipt(receipt(&hash, 121));
+    block_on(transactions::poll_receipts());
+    assert_eq!(job_status(&log_id), Some(JobStatus::Confirmed));
+
+    // Once the block of the job is no longer tracked, the job and its processed mark are pruned
+    clock.advance_secs(8 * 24 * 60 * 60);
+    for head in 121..=250 {
+        fake.set_head(head);
+        block_on(sync::sync_logs());
+    }
+    block_on(jobs::process_queue());
+    assert_eq!(job_status(&log_id), None);
+    assert!(!jobs::is_processed(&log_id));
+    let chain = chains::get(ETH_SEPOLIA_CHAIN_ID).unwrap();
+    assert_eq!(chain.pruned_block, Some(105));
+
+    // A subscription starting at the block of the pruned job could process its log again
+    let args = |start_block| SubscriptionArgs {
+        chain_id: None,
+        contract: "0xe7f1725E7734CE288F8367e1Bb143E90bb3F0512".to_string(),
+        event: None,
+        topic_filters: None,
+        start_block: Some(start_block),
+        callback: None,
+        handler: None,
+        max_gas: None,
+    };
+    assert!(block_on(subscriptions::add(args(105))).is_err());
+    block_on(subscriptions::add(args(106))).unwrap();
+}
+
+#[test]
+fn reorg_rolls_back_jobs_of_orphaned_blocks() {
+    let (fake, _) = setup();
+    fake.set_head(116);
+    let submitted = emit_new_job(&fake, 103, 1);
+    block_on(sync::sync_logs());
+    block_on(jobs::process_queue());
+    assert_eq!(job_status(&submitted), Some(JobStatus::Submitted));
+
+    fake.set_head(120);
+    let discovered = emit_new_job(&fake, 105, 2);
+    block_on(sync::sync_logs());
+    assert_eq!(job_status(&discovered), Some(JobStatus::Discovered));
+
+    // All synced blocks are replaced by blocks without the logs
+    for number in 103..=120 {
+        fake.set_block_hash(number, &replaced_hash(number));
+    }
+    fake.remove_logs_from(103);
+    block_on(sync::sync_logs());
+
+    // The job that has not been submitted is dropped, the submitted one is kept and flagged
+    assert_eq!(job_status(&discovered), None);
+    assert_eq!(job_status(&submitted), Some(JobStatus::Orphaned));
+    assert_eq!(subscriptions::get(0).unwrap().cursor, 108);
+
+    // The log of the submitted job is included again in a later block. No second callback is
+    // sent for it.
+    fake.set_head(125);
+    fake.add_log(new_job_log(110, replaced_hash(110), 1));
+    block_on(sync::sync_logs());
+    assert_eq!(job_status(&submitted), Some(JobStatus::Submitted));
+
+    block_on(jobs::process_queue());
+    assert_eq!(fake.sent_transactions().len(), 1);
+}
+
+#[test]
+fn logs_are_ingested_up_to_the_finalized_block() {
+    let (fake, _) = setup();
+    chains::set_confirmation_policy(ETH_SEPOLIA_CHAIN_ID, ConfirmationPolicy::Finalized).unwrap();
+    fake.set_head(120);
+    fake.set_finalized(104);
+    let finalized = emit_new_job(&fake, 103, 1);
+    let pending = emit_new_job(&fake, 106, 2);
+
+    block_on(sync::sync_logs());
+    assert_eq!(job_status(&finalized), Some(JobStatus::Discovered));
+    assert_eq!(job_status(&pending), None);
+    assert_eq!(subscriptions::get(0).unwrap().cursor, 104);
+}
+
+#[test]
+fn callback_is_postponed_after_a_transient_error() {
+    let (fake, _) = setup();
+    fake.set_head(120);
+    let log_id = emit_new_job(&fake, 105, 1);
+    block_on(sync::sync_logs());
+
+    fake.fail_next(
+        "eth_feeHistory",
+        RpcError::HttpOutcallError(HttpOutcallError::InvalidHttpJsonRpcResponse {
+            status: 503,
+            body: String::new(),
+            parsingError: None,
+        }),
+    );
+    block_on(jobs::process_queue());
+    assert_eq!(job_status(&log_id), Some(JobStatus::ResultReady));
+    assert!(!jobs::is_processed(&log_id));
+    assert!(fake.sent_transactions().is_empty());
+
+    block_on(jobs::process_queue());
+    assert_eq!(job_status(&log_id), Some(JobStatus::Submitted));
+    assert_eq!(fake.sent_transactions().len(), 1);
+}
+
+#[test]
+fn postponed_jobs_do_not_keep_later_jobs_from_being_processed() {
+    let (fake, _) = setup();
+    fake.set_head(130);
+    let log_ids: Vec<LogId> = (1..=11)
+        .map(|job_id| emit_new_job(&fake, 100 + job_id as u128, job_id))
+        .collect();
+    block_on(sync::sync_logs());
+
+    // The first run only picks up ten jobs, all of which are postponed
+    for _ in 0..10 {
+        fake.fail_next(
+            "eth_feeHistory",
+            RpcError::HttpOutcallError(HttpOutcallError::InvalidHttpJsonRpcResponse {
+                status: 503,
+                body: String::new(),
+                parsingError: None,
+            }),
+        );
+    }
+    block_on(jobs::process_queue());
+    assert!(log_ids[..10]
+        .iter()
+        .all(|log_id| job_status(log_id) == Some(JobStatus::ResultReady)));
+    assert_eq!(job_status(&log_ids[10]), Some(JobStatus::Discovered));
+
+    // The next run continues with the job that was left out
+    block_on(jobs::process_queue());
+    assert_eq!(job_status(&log_ids[10]), Some(JobStatus::Submitted));
+    assert_eq!(job_status(&log_ids[9]), Some(JobStatus::ResultReady));
+}
+
+#[test]
+fn callback_is_signed_again_after_the_nonce_was_used_elsewhere() {
+    let (fake, _) = setup();
+    fake.set_head(120);
+    let log_id = emit_new_job(&fake, 105, 1);
+    block_on(sync::sync_logs());
+
+    // Five transactions have been sent from the address of the canister by someone else
+    fake.set_transaction_count(&evm_address(), 5);
+    fake.push_send_status(SendRawTransactionStatus::NonceTooLow);
+    block_on(jobs::process_queue());
+
+    assert_eq!(job_status(&log_id), Some(JobStatus::Submitted));
+    let sent = fake.sent_transactions();
+    assert_eq!(sent.len(), 2);
+    assert_eq!(decode_sent(&sent[0]).nonce(), Some(&0.into()));
+    assert_eq!(decode_sent(&sent[1]).nonce(), Some(&5.into()));
+    assert_eq!(chains::get(ETH_SEPOLIA_CHAIN_ID).unwrap().nonce, 6);
+}
+
+#[test]
+fn missing_nonce_is_filled_once_it_has_been_reported_for_a_while() {
+    let (fake, clock) = setup();
+    // Nonces 0 and 1 were handed out, but their transactions never reached the network
+    chains::update(ETH_SEPOLIA_CHAIN_ID, |chain| chain.nonce = 2).unwrap();
+    fake.set_head(120);
+    let log_id = emit_new_job(&fake, 105, 1);
+    block_on(sync::sync_logs());
+
+    // A provider that has not seen a transaction yet must not get its nonce handed out again
+    for _ in 0..2 {
+        fake.push_send_status(SendRawTransactionStatus::NonceTooHigh);
+        block_on(jobs::process_queue());
+        assert_eq!(job_status(&log_id), Some(JobStatus::ResultReady));
+        assert!(chains::get(ETH_SEPOLIA_CHAIN_ID)
+            .unwrap()
+            .nonce_gaps()
+            .is_empty());
+    }
+
+    clock.advance_secs(60);
+    block_on(nonce::sync_all());
+    assert_eq!(
+        chains::get(ETH_SEPOLIA_CHAIN_ID).unwrap().nonce_gaps(),
+        vec![0]
+    );
+
+    block_on(jobs::process_queue());
+    assert_eq!(job_status(&log_id), Some(JobStatus::Submitted));
+    let nonces: Vec<_> = fake
+        .sent_transactions()
+        .iter()
+        .map(|tx| *decode_sent(tx).nonce().unwrap())
+        .collect();
+    assert_eq!(nonces, vec![2.into(), 2.into(), 0.into()]);
+}
+
+#[test]
+fn stuck_callback_is_replaced_with_higher_fees() {
+    let (fake, clock) = setup();
+    fake.set_head(120);
+    let log_id = emit_new_job(&fake, 105, 1);
+    block_on(sync::sync_logs());
+    block_on(jobs::process_queue());
+    let stuck = evm_signer::transaction_hash(&fake.sent_transactions()[0]);
+
+    let replace_after_secs = chains::get(ETH_SEPOLIA_CHAIN_ID)
+        .unwrap()
+        .fees()
+        .replace_after_secs;
+    clock.advance_secs(replace_after_secs);
+    block_on(transactions::poll_receipts());
+
+    let sent = fake.sent_transactions();
+    assert_eq!(sent.len(), 2);
+    let (original, replacement) = (decode_sent(&sent[0]), decode_sent(&sent[1]));
+    assert_eq!(original.nonce(), replacement.nonce());
+    let max_fee = |tx: &TypedTransaction| match tx {
+        TypedTransaction::Eip1559(tx) => tx.max_fee_per_gas.unwrap(),
+        _ => panic!("Callbacks are EIP-1559 transactions"),
+    };
+    assert!(max_fee(&replacement) > max_fee(&original));
+
+    let replacement = evm_signer::transaction_hash(&sent[1]);
+    assert_eq!(
+        transactions::hashes_for(&log_id),
+        vec![stuck.clone(), replacement.clone()]
+    );
+
+    // The replacement is included, which invalidates the stuck transaction
+    fake.set_receipt(receipt(&replacement, 122));
+    block_on(transactions::poll_receipts());
+    assert_eq!(job_status(&log_id), Some(JobStatus::Confirmed));
+    let status = |hash: &String| {
+        TRANSACTIONS.with(|transactions| transactions.borrow().get(hash).unwrap().status)
+    };
+    assert_eq!(status(&stuck), TransactionStatus::Replaced);
+    assert_eq!(status(&replacement), TransactionStatus::Confirmed);
+}
+
+#[test]
+fn replacement_with_unknown_send_status_is_tracked() {
+    let (fake, clock) = setup();
+    fake.set_head(120);
+    let log_id = emit_new_job(&fake, 105, 1);
+    block_on(sync::sync_logs());
+    block_on(jobs::process_queue());
+
+    clock.advance_secs(3600);
+    fake.fail_next(
+        "eth_sendRawTransaction",
+        RpcError::HttpOutcallError(HttpOutcallError::InvalidHttpJsonRpcResponse {
+            status: 503,
+            body: String::new(),
+            parsingError: None,
+        }),
+    );
+    block_on(transactions::poll_receipts());
+
+    // The replacement may have been broadcast, so its receipt is polled for
+    let hashes = transactions::hashes_for(&log_id);
+    assert_eq!(hashes.len(), 2);
+    let replacement = hashes[1].clone();
+    fake.set_receipt(receipt(&replacement, 122));
+    block_on(transactions::poll_receipts());
+    assert_eq!(job_status(&log_id), Some(JobStatus::Confirmed));
+}
+
+#[test]
+fn callback_fails_once_its_nonce_was_used_elsewhere() {
+    let (fake, clock) = setup();
+    fake.set_head(120);
+    let log_id = emit_new_job(&fake, 105, 1);
+    block_on(sync::sync_logs());
+    block_on(jobs::process_queue());
+    let stuck = evm_signer::transaction_hash(&fake.sent_transactions()[0]);
+
+    // Someone else sent a transaction with nonce 0 from the address of the canister, so the
+    // replacement is rejected and the stuck transaction never gets a receipt
+    fake.set_transaction_count(&evm_address(), 1);
+    fake.push_send_status(SendRawTransactionStatus::NonceTooLow);
+    clock.advance_secs(3600);
+    block_on(transactions::poll_receipts());
+
+    assert_eq!(job_status(&log_id), Some(JobStatus::Failed));
+    let status =
+        TRANSACTIONS.with(|transactions| transactions.borrow().get(&stuck).unwrap().status);
+    assert_eq!(status, TransactionStatus::Replaced);
+
+    // Nothing is replaced anymore
+    clock.advance_secs(3600);
+    block_on(transactions::poll_receipts());
+    assert_eq!(fake.sent_transactions().len(), 2);
+}
+
+const TOKEN: &str = "0x1f9840a85d5aF5bf1D1762F925BDADdC4201F984";
+
+/// Replies to a job with the token balance of its requester.
+struct BalanceHandler;
+
+impl JobHandler for BalanceHandler {
+    fn handle<'a>(
+        &'a self,
+        context: &'a JobContext,
+        _event: &'a DecodedEvent,
+    ) -> BoxFuture<'a, Result<JobResult, String>> {
+        Box::pin(async move {
+            let requester = context.requester.clone().ok_or("Requester unknown")?;
+            let balance = context
+                .call(
+                    TOKEN,
+                    "function balanceOf(address owner) view returns (uint256)",
+                    &[AbiValue::Address(requester)],
+                )
+                .await?;
+            match balance.as_slice() {
+                [AbiValue::Uint(balance)] => Ok(vec![AbiValue::String(balance.0.to_string())]),
+                other => Err(format!("Unexpected balance {:?}", other)),
+            }
+        })
+    }
+}
+
+#[test]
+fn rpc_request_decodes_results_and_errors_of_the_provider() {
+    let (fake, _) = setup();
+    let chain = chains::get(ETH_SEPOLIA_CHAIN_ID).unwrap();
+
+    fake.set_response("eth_chainId", serde_json::json!("0xaa36a7"));
+    let chain_id: String = block_on(evm_rpc::rpc_request(&chain, "eth_chainId", ())).unwrap();
+    assert_eq!(chain_id, "0xaa36a7");
+
+    // The fake answers methods without a scripted response with an error object
+    match block_on(evm_rpc::rpc_request::<_, String>(
+        &chain,
+        "eth_gasPrice",
+        (),
+    )) {
+        Err(CoprocessorError::JsonRpcError(error)) => assert_eq!(error.code, -32601),
+        other => panic!("Unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn job_fails_if_the_contract_accepts_callbacks_from_another_address() {
+    let (fake, _) = setup();
+    fake.set_response(
+        "eth_call",
+        coprocessor_result("0x70997970C51812dc3A010C7d01b50e0d17dc79C8"),
+    );
+    fake.set_head(120);
+    let log_id = emit_new_job(&fake, 105, 1);
+    block_on(sync::sync_logs());
+    block_on(jobs::process_queue());
+
+    assert_eq!(job_status(&log_id), Some(JobStatus::Failed));
+    assert!(fake.sent_transactions().is_empty());
+    // The address is read as of the block of the log
+    let call = &fake.requests("eth_call")[0];
+    assert_eq!(call[0]["to"], CONTRACT);
+    assert_eq!(call[1], "0x69");
+}
+
+#[test]
+fn handler_reads_contract_state_at_the_block_of_the_log() {
+    let (fake, _) = setup();
+    // Takes precedence over the default handler, which serves all contracts
+    handlers::register(
+        "balance",
+        Some(CONTRACT),
+        handlers::NEW_JOB_EVENT,
+        BalanceHandler,
+    );
+    fake.set_head(120);
+    let log_id = emit_new_job(&fake, 105, 1);
+    let requester = "0x70997970C51812dc3A010C7d01b50e0d17dc79C8";
+    fake.set_receipt(TransactionReceipt {
+        from: requester.to_string(),
+        ..receipt(&log_id.transaction_hash, 105)
+    });
+    fake.set_response("eth_call", serde_json::json!(format!("0x{:064x}", 1234)));
+
+    block_on(sync::sync_logs());
+    block_on(jobs::process_queue());
+    assert_eq!(job_status(&log_id), Some(JobStatus::Submitted));
+
+    let calls = fake.requests("eth_call");
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0][0]["to"], TOKEN);
+    let balance_of = events::parse_function("function balanceOf(address owner)").unwrap();
+    let data = hex::decode(
+        calls[0][0]["data"]
+            .as_str()
+            .unwrap()
+            .trim_start_matches("0x"),
+    )
+    .unwrap();
+    assert_eq!(
+        balance_of.decode_input(&data[4..]).unwrap()[0],
+        Token::Address(requester.parse().unwrap())
+    );
+    assert_eq!(calls[0][1], "0x69");
+
+    let tx = decode_sent(&fake.sent_transactions()[0]);
+    let callback = events::parse_function(subscriptions::DEFAULT_CALLBACK).unwrap();
+    let result = callback.decode_input(&tx.data().unwrap()[4..]).unwrap();
+    assert_eq!(result[0].clone().into_string().unwrap(), "1234");
+}
@@ -0,0 +1,370 @@
+use crate::error::CoprocessorError;
+use crate::evm_rpc::{BlockTag, SendRawTransactionStatus};
+use crate::guard::RunGuard;
+use crate::memory::{Chain, LogId, Transaction, TransactionStatus, STATE, TRANSACTIONS};
+use crate::{chains, evm_rpc, evm_signer, fees, jobs, runtime};
+
+use ethers_core::types::U256;
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeSet;
+
+/// Maximum number of receipts requested in a single run.
+const MAX_RECEIPTS_PER_RUN: usize = 10;
+
+/// Maximum number of stuck transactions replaced in a single run.
+const MAX_REPLACEMENTS_PER_RUN: usize = 5;
+
+/// Nodes only accept a transaction replacing another one with the same nonce if it raises both
+/// the maximum fee and the tip by at least this much.
+const MIN_REPLACEMENT_BUMP_PERCENT: u128 = 10;
+
+thread_local! {
+    static POLL_IN_PROGRESS: Cell<bool> = const { Cell::new(false) };
+
+    // The send time and hash of the last transaction whose receipt was requested. The next run
+    // continues after it, so that all pending transactions are polled in turn even when the
+    // transactions of replacement chains outnumber `MAX_RECEIPTS_PER_RUN`.
+    static RECEIPT_CURSOR: RefCell<Option<(u64, String)>> = const { RefCell::new(None) };
+}
+
+/// Stores a transaction that has been accepted by the network.
+pub fn record(hash: String, transaction: Transaction) {
+    TRANSACTIONS.with(|transactions| transactions.borrow_mut().insert(hash, transaction));
+}
+
+/// Returns true if a tracked transaction with the nonce may still be included in the chain,
+/// including transactions whose send status is unknown.
+pub fn is_pending(chain_id: u64, nonce: u128) -> bool {
+    TRANSACTIONS.with(|transactions| {
+        transactions.borrow().iter().any(|(_, transaction)| {
+            transaction.chain_id == chain_id
+                && transaction.nonce == nonce
+                && transaction.status == TransactionStatus::Pending
+        })
+    })
+}
+
+/// The hashes of the transactions sent for a log, in the order they were sent.
+pub fn hashes_for(log_id: &LogId) -> Vec<String> {
+    let mut sent: Vec<(String, Transaction)> = TRANSACTIONS.with(|transactions| {
+        transactions
+            .borrow()
+            .iter()
+            .filter(|(_, transaction)| &transaction.log_id == log_id)
+            .collect()
+    });
+    sent.sort_by_key(|(_, transaction)| transaction.sent_at);
+    sent.into_iter().map(|(hash, _)| hash).collect()
+}
+
+/// The status of the transaction that has been included in a block, out of the transactions of
+/// a replacement chain.
+pub fn outcome(hashes: &[String]) -> Option<TransactionStatus> {
+    TRANSACTIONS.with(|transactions| {
+        let transactions = transactions.borrow();
+        hashes
+            .iter()
+            .filter_map(|hash| transactions.get(hash))
+            .map(|transaction| transaction.status)
+            .find(|status| {
+                matches!(
+                    status,
+                    TransactionStatus::Confirmed | TransactionStatus::Reverted
+                )
+            })
+    })
+}
+
+/// Returns true if any of the transactions may still be included in the chain.
+pub fn any_pending(hashes: &[String]) -> bool {
+    TRANSACTIONS.with(|transactions| {
+        let transactions = transactions.borrow();
+        hashes
+            .iter()
+            .filter_map(|hash| transactions.get(hash))
+            .any(|transaction| transaction.status == TransactionStatus::Pending)
+    })
+}
+
+/// Removes the transactions of a job that is removed.
+pub fn remove(hashes: &[String]) {
+    TRANSACTIONS.with(|transactions| {
+        let mut transactions = transactions.borrow_mut();
+        for hash in hashes {
+            transactions.remove(hash);
+        }
+    });
+}
+
+/// Requests the receipts of pending transactions and updates the transactions and their jobs
+/// once they have been included in a block. Afterwards, transactions that are stuck are
+/// replaced.
+pub async fn poll_receipts() {
+    // Overlapping runs would request the same receipts twice
+    let _guard = match RunGuard::new(&POLL_IN_PROGRESS) {
+        Some(guard) => guard,
+        None => return,
+    };
+
+    // Transactions are checked in the order they were sent, starting after the cursor
+    let mut pending: Vec<(String, Transaction)> = TRANSACTIONS.with(|transactions| {
+        transactions
+            .borrow()
+            .iter()
+            .filter(|(_, transaction)| transaction.status == TransactionStatus::Pending)
+            .collect()
+    });
+    pending.sort_by(|(a_hash, a), (b_hash, b)| (a.sent_at, a_hash).cmp(&(b.sent_at, b_hash)));
+    let start = match RECEIPT_CURSOR.with(|cursor| cursor.borrow().clone()) {
+        Some((sent_at, cursor_hash)) => pending
+            .iter()
+            .position(|(hash, transaction)| (transaction.sent_at, hash) > (sent_at, &cursor_hash))
+            .unwrap_or(0),
+        None => 0,
+    };
+    pending.rotate_left(start);
+    pending.truncate(MAX_RECEIPTS_PER_RUN);
+    if let Some((hash, transaction)) = pending.last() {
+        RECEIPT_CURSOR.with(|cursor| {
+            *cursor.borrow_mut() = Some((transaction.sent_at, hash.clone()));
+        });
+    }
+
+    for (hash, mut transaction) in pending {
+        let chain = match chains::get(transaction.chain_id) {
+            Some(chain) => chain,
+            None => continue,
+        };
+        let receipt = match evm_rpc::get_transaction_receipt(&chain, hash.clone()).await {
+            Ok(Some(receipt)) => receipt,
+            Ok(None) => continue,
+            Err(error) => {
+                runtime::print(format!(
+                    "Failed to get the receipt of transaction {}: {}",
+                    hash, error
+                ));
+                continue;
+            }
+        };
+
+        transaction.status = if receipt.status == 1 {
+            TransactionStatus::Confirmed
+        } else {
+            TransactionStatus::Reverted
+        };
+        transaction.block_number = Some(receipt.blockNumber);
+        transaction.gas_used = Some(receipt.gasUsed);
+        transaction.effective_gas_price = Some(receipt.effectiveGasPrice);
+
+        runtime::print(format!(
+            "Transaction {} {:?} in block {}, gas used: {}",
+            hash, transaction.status, receipt.blockNumber, receipt.gasUsed
+        ));
+
+        jobs::confirm(&transaction.log_id, transaction.status);
+        mark_replaced(&hash, &transaction);
+        record(hash, transaction);
+    }
+
+    replace_stuck().await;
+}
+
+/// Replaces the transactions that have been pending for longer than `replace_after_secs` of
+/// their chain with a transaction with the same nonce and higher fees. All transactions of a
+/// replacement chain stay pending until one of them is included.
+async fn replace_stuck() {
+    let now = runtime::time();
+
+    let (replaced, pending): (BTreeSet<String>, Vec<(String, Transaction)>) =
+        TRANSACTIONS.with(|transactions| {
+            let transactions = transactions.borrow();
+            (
+                transactions
+                    .iter()
+                    .filter_map(|(_, transaction)| transaction.replaces)
+                    .collect(),
+                transactions
+                    .iter()
+                    .filter(|(_, transaction)| transaction.status == TransactionStatus::Pending)
+                    .collect(),
+            )
+        });
+
+    // Only the newest transaction of a replacement chain is replaced
+    let mut stuck: Vec<(String, Transaction)> = pending
+        .into_iter()
+        .filter(|(hash, _)| !replaced.contains(hash))
+        .filter(|(_, transaction)| match chains::get(transaction.chain_id) {
+            Some(chain) => {
+                now.saturating_sub(transaction.sent_at) / 1_000_000_000
+                    >= chain.fees().replace_after_secs
+            }
+            None => false,
+        })
+        .collect();
+    stuck.sort_by_key(|(_, transaction)| transaction.sent_at);
+    stuck.truncate(MAX_REPLACEMENTS_PER_RUN);
+
+    for (hash, transaction) in stuck {
+        if let Err(error) = replace(hash.clone(), transaction).await {
+            runtime::print(format!("Failed to replace transaction {}: {}", hash, error));
+        }
+    }
+}
+
+async fn replace(hash: String, transaction: Transaction) -> Result<(), CoprocessorError> {
+    let chain = chains::get(transaction.chain_id).ok_or(CoprocessorError::InvalidTransaction(
+        format!("Chain {} not found", transaction.chain_id),
+    ))?;
+
+    let estimate = fees::estimate(&chain).await?;
+    let max_priority_fee_per_gas = estimate
+        .max_priority_fee_per_gas
+        .max(bump(transaction.max_priority_fee_per_gas));
+    let max_fee_per_gas = estimate
+        .max_fee_per_gas
+        .max(bump(transaction.max_fee_per_gas))
+        .max(max_priority_fee_per_gas);
+    if let Some(cap) = chain.fees().max_fee_per_gas {
+        if max_fee_per_gas > cap {
+            return Err(CoprocessorError::FeeCapExceeded {
+                required: max_fee_per_gas,
+                cap,
+            });
+        }
+    }
+
+    let req = evm_signer::SignRequest {
+        chain_id: chain.network.chain_id(),
+        to: transaction.to.clone(),
+        gas: U256::from(transaction.gas_limit),
+        max_fee_per_gas: U256::from(max_fee_per_gas),
+        max_priority_fee_per_gas: U256::from(max_priority_fee_per_gas),
+        data: Some(transaction.data.clone()),
+        value: U256::from(0),
+        nonce: U256::from(transaction.nonce),
+    };
+    let tx = evm_signer::sign_transaction(req).await?;
+
+    let status = evm_rpc::send_raw_transaction(&chain, tx.clone()).await;
+    match status {
+        // If the status is unknown, the replacement may have reached the network. Like in
+        // `submit_result`, it is tracked like an accepted one, so that its receipt is found.
+        Ok(SendRawTransactionStatus::Ok) | Err(_) => {
+            let replacement = evm_signer::transaction_hash(&tx);
+            runtime::print(format!(
+                "Transaction {} replaced by {} with a maximum fee of {} wei",
+                hash, replacement, max_fee_per_gas
+            ));
+            jobs::add_transaction(&transaction.log_id, replacement.clone());
+            record(
+                replacement,
+                Transaction {
+                    replaces: Some(hash),
+                    max_fee_per_gas,
+                    max_priority_fee_per_gas,
+                    sent_at: runtime::time(),
+                    ..transaction
+                },
+            );
+            status.map(|_| ())
+        }
+        Ok(SendRawTransactionStatus::NonceTooLow) => check_nonce_used(&chain, &transaction).await,
+        Ok(SendRawTransactionStatus::NonceTooHigh) => Err(CoprocessorError::InvalidTransaction(
+            "Nonce too high".to_string(),
+        )),
+        Ok(SendRawTransactionStatus::InsufficientFunds) => Err(
+            CoprocessorError::InvalidTransaction("Insufficient funds".to_string()),
+        ),
+    }
+}
+
+/// Handles a replacement that was rejected because its nonce has been used. Usually one of the
+/// transactions of the replacement chain has been included, and its receipt is picked up by the
+/// next poll. If none of them has been included although the nonce has been mined, a
+/// transaction not sent by the coprocessor took the nonce. The callback can then never be
+/// included, so its transactions are marked as replaced and the job fails.
+async fn check_nonce_used(
+    chain: &Chain,
+    transaction: &Transaction,
+) -> Result<(), CoprocessorError> {
+    let address = STATE
+        .with(|state| state.borrow().get().evm_address.clone())
+        .ok_or(CoprocessorError::SigningError(
+            "EVM address not initialized".to_string(),
+        ))?;
+    // The count is requested before the receipts, so that a transaction of the replacement
+    // chain that is included in the meantime is found
+    let mined = evm_rpc::get_transaction_count(chain, address, BlockTag::Latest).await?;
+    if mined <= transaction.nonce {
+        return Ok(());
+    }
+
+    let siblings: Vec<String> = TRANSACTIONS.with(|transactions| {
+        transactions
+            .borrow()
+            .iter()
+            .filter(|(_, other)| {
+                other.chain_id == transaction.chain_id
+                    && other.nonce == transaction.nonce
+                    && other.status == TransactionStatus::Pending
+            })
+            .map(|(hash, _)| hash)
+            .collect()
+    });
+    for hash in &siblings {
+        if evm_rpc::get_transaction_receipt(chain, hash.clone())
+            .await?
+            .is_some()
+        {
+            return Ok(());
+        }
+    }
+
+    TRANSACTIONS.with(|transactions| {
+        let mut transactions = transactions.borrow_mut();
+        for hash in &siblings {
+            if let Some(mut sibling) = transactions.get(hash) {
+                sibling.status = TransactionStatus::Replaced;
+                transactions.insert(hash.clone(), sibling);
+            }
+        }
+    });
+    jobs::fail(
+        &transaction.log_id,
+        CoprocessorError::InvalidTransaction(format!(
+            "Nonce {} has been used by a transaction not sent by the coprocessor",
+            transaction.nonce
+        )),
+    );
+    Ok(())
+}
+
+/// Marks the other pending transactions with the same nonce as the included transaction as
+/// replaced.
+fn mark_replaced(included: &str, transaction: &Transaction) {
+    TRANSACTIONS.with(|transactions| {
+        let mut transactions = transactions.borrow_mut();
+        let siblings: Vec<(String, Transaction)> = transactions
+            .iter()
+            .filter(|(hash, other)| {
+                hash != included
+                    && other.chain_id == transaction.chain_id
+                    && other.nonce == transaction.nonce
+                    && other.status == TransactionStatus::Pending
+            })
+            .collect();
+        for (hash, mut sibling) in siblings {
+            sibling.status = TransactionStatus::Replaced;
+            transactions.insert(hash, sibling);
+        }
+    });
+}
+
+/// Raises a fee by the minimum bump accepted for replacements. Saturates instead of overflowing,
+/// the result is checked against the fee cap of the chain anyway.
+fn bump(fee: u128) -> u128 {
+    fee.saturating_add(fee.saturating_mul(MIN_REPLACEMENT_BUMP_PERCENT) / 100)
+        .saturating_add(1)
+}
@@ -0,0 +1,384 @@
+//! A stand-in for the EVM RPC canister for local end-to-end tests of the coprocessor. It serves
+//! a chain scripted with `Fixtures` instead of calling JSON-RPC providers, so the providers and
+//! RPC config of the calls are accepted but ignored.
+//!
+//! The mock never fails, so its results only have the `Consistent` and `Ok` cases of the
+//! results of the EVM RPC canister, and are decoded by the coprocessor like those.
+
+use candid::{CandidType, Deserialize, Reserved};
+
+use std::cell::RefCell;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum BlockTag {
+    Earliest,
+    Safe,
+    Finalized,
+    Latest,
+    Number(u128),
+    Pending,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LogEntry {
+    #[serde(rename = "transactionHash")]
+    pub transaction_hash: Option<String>,
+    #[serde(rename = "blockNumber")]
+    pub block_number: Option<u128>,
+    pub data: String,
+    #[serde(rename = "blockHash")]
+    pub block_hash: Option<String>,
+    #[serde(rename = "transactionIndex")]
+    pub transaction_index: Option<u128>,
+    pub topics: Vec<String>,
+    pub address: String,
+    #[serde(rename = "logIndex")]
+    pub log_index: Option<u128>,
+    pub removed: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct FeeHistory {
+    pub reward: Vec<Vec<u128>>,
+    #[serde(rename = "gasUsedRatio")]
+    pub gas_used_ratio: Vec<f64>,
+    #[serde(rename = "oldestBlock")]
+    pub oldest_block: u128,
+    #[serde(rename = "baseFeePerGas")]
+    pub base_fee_per_gas: Vec<u128>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TransactionReceipt {
+    pub to: String,
+    pub status: u128,
+    #[serde(rename = "transactionHash")]
+    pub transaction_hash: String,
+    #[serde(rename = "blockNumber")]
+    pub block_number: u128,
+    pub from: String,
+    pub logs: Vec<LogEntry>,
+    #[serde(rename = "blockHash")]
+    pub block_hash: String,
+    #[serde(rename = "type")]
+    pub tx_type: String,
+    #[serde(rename = "transactionIndex")]
+    pub transaction_index: u128,
+    #[serde(rename = "effectiveGasPrice")]
+    pub effective_gas_price: u128,
+    #[serde(rename = "logsBloom")]
+    pub logs_bloom: String,
+    #[serde(rename = "contractAddress")]
+    pub contract_address: Option<String>,
+    #[serde(rename = "gasUsed")]
+    pub gas_used: u128,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum SendRawTransactionStatus {
+    Ok,
+    NonceTooLow,
+    NonceTooHigh,
+    InsufficientFunds,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct Block {
+    pub miner: String,
+    #[serde(rename = "totalDifficulty")]
+    pub total_difficulty: u128,
+    #[serde(rename = "receiptsRoot")]
+    pub receipts_root: String,
+    #[serde(rename = "stateRoot")]
+    pub state_root: String,
+    pub hash: String,
+    pub difficulty: u128,
+    pub size: u128,
+    pub uncles: Vec<String>,
+    #[serde(rename = "baseFeePerGas")]
+    pub base_fee_per_gas: u128,
+    #[serde(rename = "extraData")]
+    pub extra_data: String,
+    #[serde(rename = "transactionsRoot")]
+    pub transactions_root: Option<String>,
+    #[serde(rename = "sha3Uncles")]
+    pub sha3_uncles: String,
+    pub nonce: u128,
+    pub number: u128,
+    pub timestamp: u128,
+    pub transactions: Vec<String>,
+    #[serde(rename = "gasLimit")]
+    pub gas_limit: u128,
+    #[serde(rename = "logsBloom")]
+    pub logs_bloom: String,
+    #[serde(rename = "parentHash")]
+    pub parent_hash: String,
+    #[serde(rename = "gasUsed")]
+    pub gas_used: u128,
+    #[serde(rename = "mixHash")]
+    pub mix_hash: String,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetLogsArgs {
+    #[serde(rename = "fromBlock")]
+    pub from_block: Option<BlockTag>,
+    #[serde(rename = "toBlock")]
+    pub to_block: Option<BlockTag>,
+    pub addresses: Vec<String>,
+    pub topics: Option<Vec<Vec<String>>>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetTransactionCountArgs {
+    pub address: String,
+    pub block: BlockTag,
+}
+
+#[derive(CandidType)]
+pub enum RpcResult<T> {
+    Ok(T),
+}
+
+#[derive(CandidType)]
+pub enum MultiRpcResult<T> {
+    Consistent(RpcResult<T>),
+}
+
+#[derive(CandidType)]
+pub enum RequestResult {
+    Ok(String),
+}
+
+#[derive(CandidType)]
+pub enum RequestCostResult {
+    Ok(u128),
+}
+
+/// The scripted state of the chain.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct Fixtures {
+    /// The latest block. The safe and finalized blocks are the same.
+    pub block_number: u128,
+    pub logs: Vec<LogEntry>,
+    pub fee_history: Option<FeeHistory>,
+    /// Returned by `eth_sendRawTransaction` in order. Transactions are accepted once the list
+    /// is exhausted.
+    pub send_statuses: Vec<SendRawTransactionStatus>,
+    pub receipts: Vec<TransactionReceipt>,
+    pub transaction_counts: Vec<(String, u128)>,
+    /// The JSON results of the methods sent with `request`, e.g. `eth_estimateGas`, by method.
+    pub responses: Vec<(String, String)>,
+}
+
+#[derive(Default)]
+struct State {
+    fixtures: Fixtures,
+    sent_transactions: Vec<String>,
+}
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::default();
+}
+
+fn with_fixtures<T>(f: impl FnOnce(&mut Fixtures) -> T) -> T {
+    STATE.with(|state| f(&mut state.borrow_mut().fixtures))
+}
+
+fn consistent<T>(value: T) -> MultiRpcResult<T> {
+    MultiRpcResult::Consistent(RpcResult::Ok(value))
+}
+
+#[ic_cdk::init]
+fn init(fixtures: Option<Fixtures>) {
+    set_fixtures(fixtures.unwrap_or_default());
+}
+
+/// Replaces the chain and forgets the transactions sent so far.
+#[ic_cdk::update]
+fn set_fixtures(fixtures: Fixtures) {
+    STATE.with(|state| {
+        *state.borrow_mut() = State {
+            fixtures,
+            sent_transactions: Vec::new(),
+        }
+    });
+}
+
+#[ic_cdk::update]
+fn set_block_number(block_number: u128) {
+    with_fixtures(|fixtures| fixtures.block_number = block_number);
+}
+
+#[ic_cdk::update]
+fn add_logs(logs: Vec<LogEntry>) {
+    with_fixtures(|fixtures| fixtures.logs.extend(logs));
+}
+
+/// Adds receipts, e.g. of the transactions returned by `get_sent_transactions`.
+#[ic_cdk::update]
+fn add_receipts(receipts: Vec<TransactionReceipt>) {
+    with_fixtures(|fixtures| fixtures.receipts.extend(receipts));
+}
+
+/// Returns the raw transactions passed to `eth_sendRawTransaction`, in order.
+#[ic_cdk::query]
+fn get_sent_transactions() -> Vec<String> {
+    STATE.with(|state| state.borrow().sent_transactions.clone())
+}
+
+#[ic_cdk::update(name = "eth_getLogs")]
+fn eth_get_logs(_: Reserved, _: Reserved, args: GetLogsArgs) -> MultiRpcResult<Vec<LogEntry>> {
+    let from = block_number(args.from_block.unwrap_or(BlockTag::Latest));
+    let to = block_number(args.to_block.unwrap_or(BlockTag::Latest));
+    let topics = args.topics.unwrap_or_default();
+    let logs = with_fixtures(|fixtures| {
+        fixtures
+            .logs
+            .iter()
+            .filter(|log| {
+                (from..=to).contains(&log.block_number.unwrap_or_default())
+                    && (args.addresses.is_empty()
+                        || args
+                            .addresses
+                            .iter()
+                            .any(|address| address.eq_ignore_ascii_case(&log.address)))
+                    && topics.iter().enumerate().all(|(i, allowed)| {
+                        allowed.is_empty()
+                            || log.topics.get(i).is_some_and(|topic| {
+                                allowed.iter().any(|t| t.eq_ignore_ascii_case(topic))
+                            })
+                    })
+            })
+            .cloned()
+            .collect()
+    });
+    consistent(logs)
+}
+
+#[ic_cdk::update(name = "eth_feeHistory")]
+fn eth_fee_history(_: Reserved, _: Reserved, _: Reserved) -> MultiRpcResult<Option<FeeHistory>> {
+    consistent(with_fixtures(|fixtures| fixtures.fee_history.clone()))
+}
+
+#[ic_cdk::update(name = "eth_getBlockByNumber")]
+fn eth_get_block_by_number(_: Reserved, _: Reserved, block: BlockTag) -> MultiRpcResult<Block> {
+    let number = block_number(block);
+    let hash = |number: u128| format!("0x{:064x}", number);
+    consistent(Block {
+        miner: format!("0x{:040x}", 0),
+        total_difficulty: 0,
+        receipts_root: hash(0),
+        state_root: hash(0),
+        hash: hash(number),
+        difficulty: 0,
+        size: 0,
+        uncles: Vec::new(),
+        base_fee_per_gas: 0,
+        extra_data: "0x".to_string(),
+        transactions_root: None,
+        sha3_uncles: hash(0),
+        nonce: 0,
+        number,
+        timestamp: 0,
+        transactions: Vec::new(),
+        gas_limit: 30_000_000,
+        logs_bloom: "0x".to_string(),
+        parent_hash: hash(number.saturating_sub(1)),
+        gas_used: 0,
+        mix_hash: hash(0),
+    })
+}
+
+#[ic_cdk::update(name = "eth_getTransactionCount")]
+fn eth_get_transaction_count(
+    _: Reserved,
+    _: Reserved,
+    args: GetTransactionCountArgs,
+) -> MultiRpcResult<u128> {
+    let count = with_fixtures(|fixtures| {
+        fixtures
+            .transaction_counts
+            .iter()
+            .find(|(address, _)| address.eq_ignore_ascii_case(&args.address))
+            .map(|(_, count)| *count)
+            .unwrap_or_default()
+    });
+    consistent(count)
+}
+
+#[ic_cdk::update(name = "eth_getTransactionReceipt")]
+fn eth_get_transaction_receipt(
+    _: Reserved,
+    _: Reserved,
+    tx_hash: String,
+) -> MultiRpcResult<Option<TransactionReceipt>> {
+    let receipt = with_fixtures(|fixtures| {
+        fixtures
+            .receipts
+            .iter()
+            .find(|receipt| receipt.transaction_hash.eq_ignore_ascii_case(&tx_hash))
+            .cloned()
+    });
+    consistent(receipt)
+}
+
+#[ic_cdk::update(name = "eth_sendRawTransaction")]
+fn eth_send_raw_transaction(
+    _: Reserved,
+    _: Reserved,
+    raw_tx: String,
+) -> MultiRpcResult<SendRawTransactionStatus> {
+    let status = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.sent_transactions.push(raw_tx);
+        if state.fixtures.send_statuses.is_empty() {
+            SendRawTransactionStatus::Ok
+        } else {
+            state.fixtures.send_statuses.remove(0)
+        }
+    });
+    consistent(status)
+}
+
+/// Answers a JSON-RPC request with the scripted result of its method, or a "Method not found"
+/// error.
+#[ic_cdk::update]
+fn request(_: Reserved, json: String, _: u64) -> RequestResult {
+    let request: serde_json::Value = serde_json::from_str(&json).unwrap_or_default();
+    let method = request["method"].as_str().unwrap_or_default();
+    let result = with_fixtures(|fixtures| {
+        fixtures
+            .responses
+            .iter()
+            .find(|(name, _)| name == method)
+            .map(|(_, result)| serde_json::from_str(result).unwrap_or(serde_json::Value::Null))
+    });
+    let response = match result {
+        Some(result) => serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": request["id"],
+            "result": result,
+        }),
+        None => serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": request["id"],
+            "error": { "code": -32601, "message": "Method not found" },
+        }),
+    };
+    RequestResult::Ok(response.to_string())
+}
+
+/// Calls to the mock are free.
+#[ic_cdk::query(name = "requestCost")]
+fn request_cost(_: Reserved, _: String, _: u64) -> RequestCostResult {
+    RequestCostResult::Ok(0)
+}
+
+fn block_number(block: BlockTag) -> u128 {
+    match block {
+        BlockTag::Number(number) => number,
+        BlockTag::Earliest => 0,
+        _ => with_fixtures(|fixtures| fixtures.block_number),
+    }
+}